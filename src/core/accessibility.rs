@@ -0,0 +1,164 @@
+use crate::core::macos_api::{
+    AXIsProcessTrusted, AXUIElementCopyAttributeValue, AXUIElementCreateApplication, CFRelease,
+    K_AX_ERROR_SUCCESS,
+};
+
+use cocoa::base::{id, nil};
+use cocoa::foundation::NSString;
+use objc::{msg_send, sel, sel_impl};
+use std::ffi::CStr;
+use std::fmt;
+use std::os::raw::c_char;
+
+/// Values accepted by the `window` condition of a rule.
+pub const WINDOW_TYPES: [&str; 7] = [
+    "normal",
+    "dialog",
+    "utility",
+    "sheet",
+    "popup",
+    "modal",
+    "transient",
+];
+
+/// How many ancestors of the focused element are checked for a sheet or popover.
+const MAX_PARENT_DEPTH: usize = 32;
+
+/// What kind of window has focus, as far as the accessibility API tells.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WindowRole {
+    /// One of `normal`, `dialog`, `utility`, `sheet` or `popup`.
+    pub kind: String,
+    /// Whether the window blocks the rest of the application.
+    pub modal: bool,
+}
+
+impl WindowRole {
+    /// Whether the window is attached to another one, like an X11 transient-for window.
+    pub fn is_transient(&self) -> bool {
+        self.kind == "sheet" || self.kind == "popup"
+    }
+
+    /// Whether the window is of `window_type`, one of [`WINDOW_TYPES`].
+    pub fn is(&self, window_type: &str) -> bool {
+        match window_type {
+            "modal" => self.modal,
+            "transient" => self.is_transient(),
+            kind => self.kind == kind,
+        }
+    }
+}
+
+impl fmt::Display for WindowRole {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.kind)?;
+        if self.modal {
+            write!(f, " (modal)")?;
+        }
+        Ok(())
+    }
+}
+
+/// Whether this process may use the accessibility API.
+pub fn is_trusted() -> bool {
+    unsafe { AXIsProcessTrusted() }
+}
+
+/// Copies an attribute value; the caller owns the result.
+unsafe fn copy_attribute(element: id, attribute: &str) -> Option<id> {
+    let attribute = NSString::alloc(nil).init_str(attribute);
+    let mut value: id = nil;
+    if AXUIElementCopyAttributeValue(element, attribute, &mut value) != K_AX_ERROR_SUCCESS
+        || value == nil
+    {
+        return None;
+    }
+    Some(value)
+}
+
+unsafe fn string_attribute(element: id, attribute: &str) -> Option<String> {
+    let value = copy_attribute(element, attribute)?;
+    let c_string: *const c_char = msg_send![value, UTF8String];
+    let result = if c_string.is_null() {
+        None
+    } else {
+        Some(CStr::from_ptr(c_string).to_string_lossy().to_string())
+    };
+    CFRelease(value);
+    result
+}
+
+unsafe fn bool_attribute(element: id, attribute: &str) -> Option<bool> {
+    let value = copy_attribute(element, attribute)?;
+    let flag: bool = msg_send![value, boolValue];
+    CFRelease(value);
+    Some(flag)
+}
+
+/// Returns `sheet` or `popup` when the focused element sits inside one.
+unsafe fn transient_container(application: id) -> Option<String> {
+    let mut element = copy_attribute(application, "AXFocusedUIElement")?;
+
+    let mut found = None;
+    for _ in 0..MAX_PARENT_DEPTH {
+        match string_attribute(element, "AXRole").as_deref() {
+            Some("AXSheet") => found = Some("sheet".to_string()),
+            Some("AXPopover") | Some("AXMenu") => found = Some("popup".to_string()),
+            Some("AXWindow") | None => {}
+            Some(_) => {
+                let parent = copy_attribute(element, "AXParent");
+                CFRelease(element);
+                match parent {
+                    Some(parent) => {
+                        element = parent;
+                        continue;
+                    }
+                    None => return None,
+                }
+            }
+        }
+        break;
+    }
+
+    CFRelease(element);
+    found
+}
+
+/// Returns the role of the focused window of the application with `pid`, or `None`
+/// when the accessibility API is not available to us.
+///
+/// # Safety
+///
+/// This function is unsafe because it calls Accessibility and CoreFoundation FFI functions.
+pub unsafe fn focused_window_role(pid: i32) -> Option<WindowRole> {
+    let application = AXUIElementCreateApplication(pid);
+    if application == nil {
+        return None;
+    }
+
+    let role = copy_attribute(application, "AXFocusedWindow").map(|window| {
+        let kind = match string_attribute(window, "AXSubrole").as_deref() {
+            Some("AXDialog") | Some("AXSystemDialog") => "dialog",
+            Some("AXFloatingWindow") | Some("AXSystemFloatingWindow") => "utility",
+            _ => "normal",
+        };
+        let modal = bool_attribute(window, "AXModal").unwrap_or(false);
+        CFRelease(window);
+
+        WindowRole {
+            kind: kind.to_string(),
+            modal,
+        }
+    });
+
+    let role = match (role, transient_container(application)) {
+        (Some(role), Some(kind)) => Some(WindowRole {
+            kind,
+            modal: role.modal,
+        }),
+        (role, _) => role,
+    };
+
+    CFRelease(application);
+    role
+}
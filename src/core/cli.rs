@@ -0,0 +1,483 @@
+use crate::core::client::{self, ClientError};
+use crate::core::config::{self, Config};
+use crate::core::rules::app_matches;
+use crate::core::sources::{self, SourceCatalogue};
+use crate::core::{inventory, monitor, switcher};
+use crate::state;
+
+use cocoa::base::{id, nil};
+use cocoa::foundation::NSAutoreleasePool;
+use objc::{class, msg_send, sel, sel_impl};
+use serde_json::{json, Value};
+use std::ffi::CStr;
+use std::os::raw::c_char;
+use std::rc::Rc;
+
+pub const USAGE: &str = "\
+Usage: language-handler [COMMAND]
+
+Commands:
+  run                         Switch layouts as applications take focus (the default)
+  status                      Show whether Language Handler is running and what it is doing
+  list-layouts [--json]       List the input sources and the codes that match them
+  list-apps                   List the running applications and their rules
+  validate                    Check the config file
+  rule list                   List the switching rules
+  rule add <app> <layout>...  Add a rule for an application without one
+  rule set <app> <layout>...  Change the layouts of an application's rules
+  rule remove <app>           Remove the rules for an application
+  switch <layout>             Switch to a layout now
+  reload                      Make the running instance read the config file again
+  pause                       Stop switching layouts until resumed
+  resume                      Start switching layouts again
+  profile <name|none>         Use the rules of a profile, or only the base rules
+  events                      Print the events of the running instance as JSON lines
+  help                        Show this help
+
+Commands other than run talk to the running instance when there is one, and work
+on the config file directly otherwise.";
+
+/// A command given on the command line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    Run,
+    Status,
+    ListLayouts { json: bool },
+    ListApps,
+    Validate,
+    ListRules,
+    AddRule { app: String, layouts: Vec<String> },
+    SetRule { app: String, layouts: Vec<String> },
+    RemoveRule { app: String },
+    Switch { layout: String },
+    Reload,
+    Pause,
+    Resume,
+    SetProfile { profile: Option<String> },
+    Events,
+    Help,
+}
+
+/// Parses the arguments after the program name. No arguments means `run`.
+pub fn parse_args(args: &[String]) -> Result<Command, String> {
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let command = match args.as_slice() {
+        [] | ["run"] => Command::Run,
+        ["status"] => Command::Status,
+        ["list-layouts"] => Command::ListLayouts { json: false },
+        ["list-layouts", "--json"] => Command::ListLayouts { json: true },
+        ["list-apps"] => Command::ListApps,
+        ["validate"] => Command::Validate,
+        ["rule", "list"] => Command::ListRules,
+        ["rule", "add", app, layouts @ ..] if !layouts.is_empty() => Command::AddRule {
+            app: app.to_string(),
+            layouts: layouts.iter().map(|layout| layout.to_string()).collect(),
+        },
+        ["rule", "add", ..] => return Err("usage: rule add <app> <layout>...".to_string()),
+        ["rule", "set", app, layouts @ ..] if !layouts.is_empty() => Command::SetRule {
+            app: app.to_string(),
+            layouts: layouts.iter().map(|layout| layout.to_string()).collect(),
+        },
+        ["rule", "set", ..] => return Err("usage: rule set <app> <layout>...".to_string()),
+        ["rule", "remove", app] => Command::RemoveRule {
+            app: app.to_string(),
+        },
+        ["rule", "remove", ..] => return Err("usage: rule remove <app>".to_string()),
+        ["rule", ..] => return Err("usage: rule list | add | set | remove".to_string()),
+        ["switch", layout] => Command::Switch {
+            layout: layout.to_string(),
+        },
+        ["switch", ..] => return Err("usage: switch <layout>".to_string()),
+        ["reload"] => Command::Reload,
+        ["pause"] => Command::Pause,
+        ["resume"] => Command::Resume,
+        ["profile", "none"] => Command::SetProfile { profile: None },
+        ["profile", profile] => Command::SetProfile {
+            profile: Some(profile.to_string()),
+        },
+        ["events"] => Command::Events,
+        ["help"] | ["--help"] | ["-h"] => Command::Help,
+        [command, ..] => return Err(format!("unknown command '{}'", command)),
+    };
+    Ok(command)
+}
+
+/// Runs `command` and returns the exit code.
+pub fn execute(command: Command) -> i32 {
+    match command {
+        Command::Run => {
+            crate::run();
+            0
+        }
+        Command::Status => status(),
+        Command::ListLayouts { json } => list_layouts(json),
+        Command::ListApps => list_apps(),
+        Command::Validate => validate(),
+        Command::ListRules => list_rules(),
+        Command::AddRule { app, layouts } => match config::add_rule(&app, &layouts) {
+            Ok(()) => {
+                println!("Added {} -> {}", app, layouts.join(", "));
+                notify_reload()
+            }
+            Err(e) => fail(&e),
+        },
+        Command::SetRule { app, layouts } => match config::set_rule(&app, &layouts) {
+            Ok(changed) => {
+                if changed == 0 {
+                    println!("Added {} -> {}", app, layouts.join(", "));
+                } else {
+                    println!(
+                        "Changed {} rule{} for {} to {}",
+                        changed,
+                        if changed == 1 { "" } else { "s" },
+                        app,
+                        layouts.join(", ")
+                    );
+                }
+                notify_reload()
+            }
+            Err(e) => fail(&e),
+        },
+        Command::RemoveRule { app } => match config::remove_rule(&app) {
+            Ok(removed) => {
+                println!(
+                    "Removed {} rule{} for {}",
+                    removed,
+                    if removed == 1 { "" } else { "s" },
+                    app
+                );
+                notify_reload()
+            }
+            Err(e) => fail(&e),
+        },
+        Command::Switch { layout } => switch(&layout),
+        Command::Reload => daemon_only("reload"),
+        Command::Pause => daemon_only("pause"),
+        Command::Resume => daemon_only("resume"),
+        Command::SetProfile { profile } => set_profile(profile),
+        Command::Events => events(),
+        Command::Help => {
+            println!("{}", USAGE);
+            0
+        }
+    }
+}
+
+fn fail(message: &str) -> i32 {
+    eprintln!("Error: {}", message);
+    1
+}
+
+/// Prints a result from the running instance: text as it is, a list one item per
+/// line, an object one field per line.
+fn print_result(result: &Value) {
+    match result {
+        Value::Null => {}
+        Value::String(text) => println!("{}", text),
+        Value::Array(items) => {
+            for item in items {
+                print_result(item);
+            }
+        }
+        Value::Object(fields) => {
+            for (key, value) in fields {
+                match value {
+                    Value::String(text) => println!("{}: {}", key, text),
+                    Value::Null => println!("{}: -", key),
+                    _ => println!("{}: {}", key, value),
+                }
+            }
+        }
+        _ => println!("{}", result),
+    }
+}
+
+/// Sends `method` to the running instance; there is nothing to do without one.
+fn daemon_only(method: &str) -> i32 {
+    match client::request(method, json!({})) {
+        Ok(result) => {
+            print_result(&result);
+            0
+        }
+        Err(e) => fail(&e.to_string()),
+    }
+}
+
+fn set_profile(profile: Option<String>) -> i32 {
+    match client::request("set_profile", json!({ "profile": profile })) {
+        Ok(_) => {
+            println!("Profile: {}", profile.as_deref().unwrap_or("none"));
+            0
+        }
+        Err(e) => fail(&e.to_string()),
+    }
+}
+
+/// Prints each event of the running instance on its own line until it exits.
+fn events() -> i32 {
+    match client::subscribe(|event| println!("{}", event)) {
+        Ok(()) => 0,
+        Err(e) => fail(&e.to_string()),
+    }
+}
+
+/// Asks the running instance, if any, to pick up a changed config file.
+fn notify_reload() -> i32 {
+    match client::request("reload", json!({})) {
+        Ok(_) => {
+            println!("The running instance reloaded the config");
+            0
+        }
+        Err(ClientError::NotRunning) => 0,
+        Err(e) => fail(&format!(
+            "the config was saved, but reloading it failed: {}",
+            e
+        )),
+    }
+}
+
+/// Reads the config file, falling back to the default rules with a warning.
+fn read_config_or_default() -> Config {
+    config::read_config().unwrap_or_else(|e| {
+        eprintln!("Warning: {}. Using the default rules.", e);
+        config::read_default_config()
+    })
+}
+
+fn status() -> i32 {
+    match client::request("status", json!({})) {
+        Ok(result) => {
+            print_result(&result);
+            return 0;
+        }
+        Err(ClientError::NotRunning) => {}
+        Err(e) => return fail(&e.to_string()),
+    }
+
+    println!("Language Handler is not running");
+    println!("Config file: {}", config::get_config_path().display());
+    match config::read_config() {
+        Ok(config) => println!("Rules: {}", config.rules.len()),
+        Err(e) => println!("Config error: {}", e),
+    }
+    unsafe {
+        let _pool = NSAutoreleasePool::new(nil);
+        monitor::update_keyboard_layout();
+        if let Some(layout) = &*std::ptr::addr_of!(state::CURRENT_KEYBOARD_LAYOUT) {
+            println!("Layout: {}", layout);
+        }
+    }
+    0
+}
+
+fn list_rules() -> i32 {
+    match client::request("list_rules", json!({})) {
+        Ok(result) => {
+            print_result(&result);
+            return 0;
+        }
+        Err(ClientError::NotRunning) => {}
+        Err(e) => return fail(&e.to_string()),
+    }
+
+    match config::read_config() {
+        Ok(config) => {
+            for rule in &config.rules {
+                println!("{}", rule);
+            }
+            0
+        }
+        Err(e) => fail(&e),
+    }
+}
+
+/// Prints every installed input source and the config codes that resolve to it, as a
+/// table or as JSON.
+fn list_layouts(as_json: bool) -> i32 {
+    let config = read_config_or_default();
+
+    unsafe {
+        let _pool = NSAutoreleasePool::new(nil);
+
+        state::CONFIG = Some(config);
+        state::SOURCE_CATALOGUE = Some(Rc::new(SourceCatalogue::load()));
+
+        let catalogue = sources::catalogue();
+        let entries = inventory::list_sources(&catalogue);
+        if as_json {
+            match serde_json::to_string_pretty(&inventory::to_json(&entries)) {
+                Ok(output) => println!("{}", output),
+                Err(e) => return fail(&format!("could not serialize input sources: {}", e)),
+            }
+        } else {
+            inventory::print_table(&entries);
+        }
+    }
+    0
+}
+
+unsafe fn ns_string(value: id) -> Option<String> {
+    if value == nil {
+        return None;
+    }
+    let c_string: *const c_char = msg_send![value, UTF8String];
+    if c_string.is_null() {
+        return None;
+    }
+    Some(CStr::from_ptr(c_string).to_string_lossy().to_string())
+}
+
+/// Prints the running applications that have a Dock icon under the names rules use,
+/// with the first rule written for each.
+fn list_apps() -> i32 {
+    let config = read_config_or_default();
+
+    unsafe {
+        let _pool = NSAutoreleasePool::new(nil);
+        let workspace: id = msg_send![class!(NSWorkspace), sharedWorkspace];
+        let apps: id = msg_send![workspace, runningApplications];
+        let count: usize = msg_send![apps, count];
+
+        let mut rows: Vec<(String, String, String)> = Vec::new();
+        for i in 0..count {
+            let app: id = msg_send![apps, objectAtIndex: i];
+            // NSApplicationActivationPolicyRegular
+            let policy: isize = msg_send![app, activationPolicy];
+            if policy != 0 {
+                continue;
+            }
+
+            let name = match ns_string(msg_send![app, localizedName]) {
+                Some(name) => name,
+                None => continue,
+            };
+            let bundle_id = ns_string(msg_send![app, bundleIdentifier]).unwrap_or_default();
+            let rule = config
+                .rules
+                .iter()
+                .find(|rule| {
+                    rule.app
+                        .as_deref()
+                        .is_some_and(|app| app_matches(&name, app))
+                })
+                .map(|rule| rule.layouts.join(", "))
+                .unwrap_or_else(|| "-".to_string());
+            rows.push((name, bundle_id, rule));
+        }
+        rows.sort_by_key(|(name, _, _)| name.to_lowercase());
+
+        let name_width = rows.iter().map(|(name, _, _)| name.chars().count()).max();
+        let bundle_width = rows.iter().map(|(_, bundle, _)| bundle.len()).max();
+        let name_width = name_width.unwrap_or(0).max("APP".len());
+        let bundle_width = bundle_width.unwrap_or(0).max("BUNDLE ID".len());
+
+        println!(
+            "{:<name_width$}  {:<bundle_width$}  LAYOUT",
+            "APP", "BUNDLE ID"
+        );
+        for (name, bundle_id, rule) in rows {
+            println!(
+                "{:<name_width$}  {:<bundle_width$}  {}",
+                name, bundle_id, rule
+            );
+        }
+    }
+    0
+}
+
+/// Loads the config file and checks that every layout it mentions matches an
+/// installed input source. Exits with 1 when there are errors or warnings.
+fn validate() -> i32 {
+    let config = match config::read_config() {
+        Ok(config) => config,
+        Err(e) => return fail(&e),
+    };
+
+    // Profiles that are not active are checked too, so that switching to one later
+    // does not bring up problems of its own.
+    let mut layouts: Vec<String> = config
+        .all_rules()
+        .flat_map(|rule| rule.layouts.iter().cloned())
+        .chain(config.default_layout.clone())
+        .chain(config.aliases.iter().map(|(alias, _)| alias.clone()))
+        .collect();
+    layouts.sort();
+    layouts.dedup();
+    let rule_count = config.all_rules().count();
+    let enable_missing = config.enable_missing_layouts;
+
+    let mut problems = config.warnings.len();
+    for warning in &config.warnings {
+        println!("Warning: {}", warning);
+    }
+    unsafe {
+        let _pool = NSAutoreleasePool::new(nil);
+
+        state::CONFIG = Some(config);
+        state::SOURCE_CATALOGUE = Some(Rc::new(SourceCatalogue::load()));
+
+        let catalogue = sources::catalogue();
+        for layout in &layouts {
+            match switcher::candidate_sources(&catalogue, layout, true).first() {
+                Some(candidate) if candidate.source.enabled || enable_missing => {}
+                Some(candidate) => {
+                    println!(
+                        "Warning: '{}' -> {} is installed but not enabled",
+                        layout, candidate.source.id
+                    );
+                    problems += 1;
+                }
+                None => {
+                    println!(
+                        "Warning: '{}' does not match any installed input source",
+                        layout
+                    );
+                    problems += 1;
+                }
+            }
+        }
+    }
+
+    println!(
+        "{}: {} rule{}, {} layout{} checked, {} warning{}",
+        config::get_config_path().display(),
+        rule_count,
+        if rule_count == 1 { "" } else { "s" },
+        layouts.len(),
+        if layouts.len() == 1 { "" } else { "s" },
+        problems,
+        if problems == 1 { "" } else { "s" }
+    );
+    if problems > 0 {
+        1
+    } else {
+        0
+    }
+}
+
+/// Asks the running instance to switch, or switches directly without one.
+fn switch(layout: &str) -> i32 {
+    match client::request("switch", json!({ "layout": layout })) {
+        Ok(result) => {
+            print_result(&result);
+            return 0;
+        }
+        Err(ClientError::NotRunning) => {}
+        Err(e) => return fail(&e.to_string()),
+    }
+
+    let config = read_config_or_default();
+    unsafe {
+        let _pool = NSAutoreleasePool::new(nil);
+
+        state::CONFIG = Some(config);
+        monitor::update_keyboard_layout();
+
+        let outcome = switcher::switch_to_layout_now(layout);
+        if outcome.is_failure() {
+            return fail(&format!("could not switch to '{}': {}", layout, outcome));
+        }
+        println!("{}", outcome);
+    }
+    0
+}
@@ -0,0 +1,109 @@
+use crate::core::{config, control};
+
+use serde_json::{json, Value};
+use std::fmt;
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::os::unix::net::UnixStream;
+use std::time::Duration;
+
+/// How long to wait for a running instance to answer: longer than it waits for a
+/// request to be carried out, so that a slow request is reported by the instance
+/// rather than given up on while it still goes ahead.
+const REQUEST_TIMEOUT: Duration = control::REPLY_TIMEOUT.saturating_add(Duration::from_secs(2));
+
+#[derive(Debug)]
+pub enum ClientError {
+    /// No instance is listening on the control socket.
+    NotRunning,
+    /// The request could not be made, or the instance reported an error.
+    Failed(String),
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientError::NotRunning => write!(f, "Language Handler is not running"),
+            ClientError::Failed(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+fn connect() -> Result<UnixStream, ClientError> {
+    let socket_path = config::get_socket_path();
+    match UnixStream::connect(&socket_path) {
+        Ok(stream) => Ok(stream),
+        Err(e) if matches!(e.kind(), ErrorKind::NotFound | ErrorKind::ConnectionRefused) => {
+            Err(ClientError::NotRunning)
+        }
+        Err(e) => Err(ClientError::Failed(format!(
+            "could not connect to {}: {}",
+            socket_path.display(),
+            e
+        ))),
+    }
+}
+
+fn failed(e: std::io::Error) -> ClientError {
+    ClientError::Failed(format!("control request failed: {}", e))
+}
+
+fn send(stream: &mut UnixStream, method: &str, params: Value) -> Result<(), ClientError> {
+    let request = json!({ "jsonrpc": "2.0", "id": 1, "method": method, "params": params });
+    writeln!(stream, "{}", request).map_err(failed)
+}
+
+fn read_message(reader: &mut impl BufRead) -> Result<Value, ClientError> {
+    let mut line = String::new();
+    if reader.read_line(&mut line).map_err(failed)? == 0 {
+        return Err(ClientError::Failed("the connection was closed".to_string()));
+    }
+    serde_json::from_str(&line).map_err(|e| ClientError::Failed(format!("invalid response: {}", e)))
+}
+
+fn result_of(response: Value) -> Result<Value, ClientError> {
+    if let Some(error) = response.get("error") {
+        let message = error
+            .get("message")
+            .and_then(Value::as_str)
+            .unwrap_or("unknown error");
+        return Err(ClientError::Failed(message.to_string()));
+    }
+    response
+        .get("result")
+        .cloned()
+        .ok_or_else(|| ClientError::Failed("response has no result".to_string()))
+}
+
+/// Sends a JSON-RPC request to the running instance over the control socket and
+/// returns its result. Requests and responses are one JSON object per line.
+pub fn request(method: &str, params: Value) -> Result<Value, ClientError> {
+    let mut stream = connect()?;
+    stream
+        .set_read_timeout(Some(REQUEST_TIMEOUT))
+        .map_err(failed)?;
+    stream
+        .set_write_timeout(Some(REQUEST_TIMEOUT))
+        .map_err(failed)?;
+
+    send(&mut stream, method, params)?;
+    result_of(read_message(&mut BufReader::new(stream))?)
+}
+
+/// Subscribes to the events of the running instance and passes the parameters of
+/// each one to `on_event` until the connection closes.
+pub fn subscribe(mut on_event: impl FnMut(&Value)) -> Result<(), ClientError> {
+    let mut stream = connect()?;
+    stream
+        .set_write_timeout(Some(REQUEST_TIMEOUT))
+        .map_err(failed)?;
+    send(&mut stream, "subscribe", json!({}))?;
+
+    let mut reader = BufReader::new(stream);
+    result_of(read_message(&mut reader)?)?;
+    loop {
+        let message = read_message(&mut reader)?;
+        if let Some(params) = message.get("params") {
+            on_event(params);
+        }
+    }
+}
@@ -1,7 +1,159 @@
-use serde_json;
-use std::collections::HashMap;
-use std::fs;
+use crate::core::accessibility::{self, WINDOW_TYPES};
+use crate::core::json_edit::{self, Node};
+use crate::core::layouts::{self, LayoutTable};
+use crate::core::rules::{app_matches, Rule};
+use crate::core::schedule;
+
+use serde_json::{self, Map, Value};
+use std::fs::{self, File};
+use std::io::{self, Write};
 use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// How much of the user's own layout choices the engine remembers between focus changes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryMode {
+    /// Always apply the configured rules.
+    Off,
+    /// Restore the layout last used in the application.
+    App,
+    /// Restore the layout last used in the window, then in the application.
+    Window,
+}
+
+impl MemoryMode {
+    pub fn parse(value: &str) -> Option<MemoryMode> {
+        match value.to_lowercase().as_str() {
+            "off" | "none" | "false" => Some(MemoryMode::Off),
+            "app" | "application" => Some(MemoryMode::App),
+            "window" => Some(MemoryMode::Window),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MemoryMode::Off => "off",
+            MemoryMode::App => "app",
+            MemoryMode::Window => "window",
+        }
+    }
+}
+
+/// Applications in which `.language-handler` project files are looked up.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProjectFiles {
+    Off,
+    AllApps,
+    Apps(Vec<String>),
+}
+
+impl ProjectFiles {
+    pub fn enabled_for(&self, app_name: &str) -> bool {
+        match self {
+            ProjectFiles::Off => false,
+            ProjectFiles::AllApps => true,
+            ProjectFiles::Apps(apps) => apps.iter().any(|app| app_matches(app_name, app)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Rules in effect: those of the active profile, then the others.
+    pub rules: Vec<Rule>,
+    pub memory: MemoryMode,
+    /// Layout for applications without a rule; `None` keeps the current one.
+    pub default_layout: Option<String>,
+    /// Applications whose layout is never changed.
+    pub exclude: Vec<String>,
+    /// Applications that only take focus briefly; the previous application's layout
+    /// is restored when they close.
+    pub overlays: Vec<String>,
+    /// How long a manually selected layout is left alone; `None` keeps it until focus moves.
+    pub grace_period: Option<Duration>,
+    /// How long an application has to keep focus before its layout is applied, so that
+    /// applications passed over while cycling with Cmd-Tab are skipped.
+    pub dwell: Option<Duration>,
+    pub project_files: ProjectFiles,
+    /// Shell command run when a layout switch fails for good.
+    pub on_switch_failure: Option<String>,
+    /// Layout codes and how to recognize them: the built-in table plus the config's.
+    pub layouts: LayoutTable,
+    /// User-defined names for layout targets, e.g. `mine` for an input source id.
+    pub aliases: Vec<(String, String)>,
+    /// Whether an input source that a rule needs but that is not enabled in System
+    /// Settings is enabled automatically.
+    pub enable_missing_layouts: bool,
+    /// Named sets of rules that can be switched on while running.
+    pub profiles: Vec<(String, Vec<Rule>)>,
+    /// Name of the active profile.
+    pub profile: Option<String>,
+    /// Rules outside of any profile.
+    base_rules: Vec<Rule>,
+    /// Problems that did not stop the config from loading, e.g. unknown options.
+    pub warnings: Vec<String>,
+}
+
+impl Config {
+    fn with_rules(rules: Vec<Rule>) -> Config {
+        Config {
+            base_rules: rules.clone(),
+            rules,
+            memory: MemoryMode::Off,
+            default_layout: None,
+            exclude: Vec::new(),
+            overlays: Vec::new(),
+            grace_period: None,
+            dwell: None,
+            project_files: ProjectFiles::Off,
+            on_switch_failure: None,
+            layouts: LayoutTable::builtin(),
+            aliases: Vec::new(),
+            enable_missing_layouts: false,
+            profiles: Vec::new(),
+            profile: None,
+            warnings: Vec::new(),
+        }
+    }
+
+    /// Makes the rules of profile `name` take precedence over the others, or goes back
+    /// to the rules outside of profiles with `None`.
+    pub fn set_profile(&mut self, name: Option<&str>) -> Result<(), String> {
+        let profile = match name {
+            Some(name) => Some(
+                self.profiles
+                    .iter()
+                    .find(|(profile, _)| profile.eq_ignore_ascii_case(name))
+                    .ok_or_else(|| format!("unknown profile '{}'", name))?,
+            ),
+            None => None,
+        };
+
+        self.rules = match profile {
+            Some((_, rules)) => rules.iter().chain(&self.base_rules).cloned().collect(),
+            None => self.base_rules.clone(),
+        };
+        self.profile = profile.map(|(profile, _)| profile.clone());
+        Ok(())
+    }
+
+    /// Every rule in the config: those outside of profiles, then those of each profile,
+    /// whether it is active or not.
+    pub fn all_rules(&self) -> impl Iterator<Item = &Rule> {
+        self.base_rules
+            .iter()
+            .chain(self.profiles.iter().flat_map(|(_, rules)| rules))
+    }
+
+    /// The target the user alias `name` stands for, compared without regard to case.
+    pub fn alias_target(&self, name: &str) -> Option<&str> {
+        self.aliases
+            .iter()
+            .find(|(alias, _)| alias.eq_ignore_ascii_case(name))
+            .map(|(_, target)| target.as_str())
+    }
+}
 
 pub fn get_config_path() -> PathBuf {
     let home = std::env::var("HOME").unwrap_or_else(|_| "/Users/default".to_string());
@@ -12,71 +164,905 @@ pub fn get_config_path() -> PathBuf {
         .join("config.json")
 }
 
-fn create_default_config() -> HashMap<String, String> {
-    let mut config = HashMap::new();
-    config.insert("Terminal".to_string(), "US".to_string());
-    config.insert("iTerm2".to_string(), "US".to_string());
-    config.insert("iTerm".to_string(), "US".to_string());
-    config.insert("Code".to_string(), "US".to_string());
-    config.insert("Visual Studio Code".to_string(), "US".to_string());
-    config.insert("Xcode".to_string(), "US".to_string());
-    config
+/// Path of the socket a running instance accepts control requests on.
+pub fn get_socket_path() -> PathBuf {
+    get_config_path().with_file_name("control.sock")
+}
+
+/// The rules a new config file starts with, in the order they are written.
+fn create_default_config() -> Vec<(String, String)> {
+    [
+        "Terminal",
+        "iTerm2",
+        "iTerm",
+        "Code",
+        "Visual Studio Code",
+        "Xcode",
+    ]
+    .iter()
+    .map(|app| (app.to_string(), "US".to_string()))
+    .collect()
+}
+
+/// The text of a new config file: the default rules as a plain map.
+fn default_config_text() -> String {
+    let rules: Vec<String> = create_default_config()
+        .iter()
+        .map(|(app, layout)| {
+            format!(
+                "  {}: {}",
+                Value::String(app.clone()),
+                Value::String(layout.clone())
+            )
+        })
+        .collect();
+    format!("{{\n{}\n}}\n", rules.join(",\n"))
+}
+
+/// Replaces the file at `path` with `content` by writing a temporary file next to it
+/// and renaming it over the old one, so that the file is never left half-written. A
+/// symlink is followed, and the file keeps its permissions.
+fn write_replacing(path: &Path, content: &str) -> io::Result<()> {
+    let path = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    let mut temp_name = path.file_name().unwrap_or_default().to_os_string();
+    temp_name.push(".tmp");
+    let temp_path = path.with_file_name(temp_name);
+
+    let result = (|| {
+        let mut file = File::create(&temp_path)?;
+        file.write_all(content.as_bytes())?;
+        file.sync_all()?;
+        if let Ok(metadata) = fs::metadata(&path) {
+            fs::set_permissions(&temp_path, metadata.permissions())?;
+        }
+        fs::rename(&temp_path, &path)
+    })();
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+    result
+}
+
+fn parse_string_list(field: &str, value: &Value) -> Result<Vec<String>, String> {
+    if let Some(item) = value.as_str() {
+        return Ok(vec![item.to_string()]);
+    }
+
+    let error = || format!("{} must be a string or a list of strings", field);
+    value
+        .as_array()
+        .ok_or_else(error)?
+        .iter()
+        .map(|item| item.as_str().map(str::to_string).ok_or_else(error))
+        .collect()
+}
+
+/// Parses a layout string or a non-empty list of layout strings.
+fn parse_layouts(app: &str, value: &Value) -> Result<Vec<String>, String> {
+    let layouts = parse_string_list(&format!("layout for '{}'", app), value)?;
+    for layout in &layouts {
+        if let (layout, Some(mode)) = layouts::split_input_mode(layout) {
+            if layout.is_empty() || mode.is_empty() {
+                return Err(format!(
+                    "layout for '{}' must be written as LAYOUT:MODE to pick an input mode",
+                    app
+                ));
+            }
+        }
+    }
+    if layouts.is_empty() {
+        return Err(format!("layout list for '{}' is empty", app));
+    }
+    Ok(layouts)
+}
+
+/// Parses a rule written as a layout string, a list of allowed layouts, or as an
+/// object such as `{ "layout": "EN", "enforce": true, "process": "vim", "days": "mon-fri" }`.
+/// `app` comes from the key in the map form; in the list form rules are objects
+/// with an optional `app` field.
+fn parse_rule(app: Option<&str>, value: &Value) -> Result<Rule, String> {
+    let object = match (app, value.as_object()) {
+        (_, Some(object)) => object,
+        (Some(app), None) => {
+            return Ok(Rule::with_layouts(
+                Some(app.to_string()),
+                parse_layouts(app, value)?,
+            ))
+        }
+        (None, None) => return Err("rules in a list must be objects".to_string()),
+    };
+
+    let app = match (app, object.get("app")) {
+        (Some(app), _) => Some(app.to_string()),
+        (None, None) => None,
+        (None, Some(app)) => match app.as_str() {
+            Some(app) => Some(app.to_string()),
+            None => return Err("app must be a string".to_string()),
+        },
+    };
+    let name = app.clone().unwrap_or_else(|| "*".to_string());
+
+    let layouts = match object.get("layout") {
+        Some(layout) => parse_layouts(&name, layout)?,
+        None => return Err(format!("rule for '{}' needs a \"layout\"", name)),
+    };
+
+    let mut rule = Rule::with_layouts(app, layouts);
+
+    if let Some(enforce) = object.get("enforce") {
+        rule.enforce = enforce
+            .as_bool()
+            .ok_or_else(|| format!("enforce for '{}' must be true or false", name))?;
+    }
+
+    if let Some(process) = object.get("process") {
+        rule.processes = parse_string_list(&format!("process for '{}'", name), process)?;
+    }
+
+    if let Some(args) = object.get("args") {
+        match args.as_str() {
+            Some(args) => rule.args = Some(args.to_string()),
+            None => return Err(format!("args for '{}' must be a string", name)),
+        }
+    }
+
+    if let Some(workspaces) = object.get("workspace") {
+        rule.workspaces = parse_string_list(&format!("workspace for '{}'", name), workspaces)?;
+    }
+
+    if let Some(outputs) = object.get("output") {
+        rule.outputs = parse_string_list(&format!("output for '{}'", name), outputs)?;
+    }
+
+    if let Some(window_types) = object.get("window") {
+        for window_type in parse_string_list(&format!("window for '{}'", name), window_types)? {
+            let window_type = window_type.to_lowercase();
+            if !WINDOW_TYPES.contains(&window_type.as_str()) {
+                return Err(format!(
+                    "unknown window type '{}' for '{}', expected one of: {}",
+                    window_type,
+                    name,
+                    WINDOW_TYPES.join(", ")
+                ));
+            }
+            rule.window_types.push(window_type);
+        }
+    }
+
+    if let Some(times) = object.get("time") {
+        for window in parse_string_list(&format!("time for '{}'", name), times)? {
+            rule.schedule
+                .times
+                .push(schedule::parse_time_window(&window)?);
+        }
+    }
+
+    if let Some(days) = object.get("days") {
+        for days in parse_string_list(&format!("days for '{}'", name), days)? {
+            rule.schedule.weekdays |= schedule::parse_weekdays(&days)?;
+        }
+    }
+
+    if let Some(dates) = object.get("dates") {
+        for range in parse_string_list(&format!("dates for '{}'", name), dates)? {
+            rule.schedule
+                .dates
+                .push(schedule::parse_date_range(&range)?);
+        }
+    }
+
+    Ok(rule)
+}
+
+fn parse_rule_map(object: &Map<String, Value>) -> Result<Vec<Rule>, String> {
+    object
+        .iter()
+        .map(|(app, rule)| parse_rule(Some(app), rule))
+        .collect()
+}
+
+/// Parses the `rules` section: either an `"App": rule` map or an ordered list of
+/// rule objects that name their `app` themselves.
+fn parse_rules(value: &Value) -> Result<Vec<Rule>, String> {
+    match value {
+        Value::Object(object) => parse_rule_map(object),
+        Value::Array(list) => list.iter().map(|rule| parse_rule(None, rule)).collect(),
+        _ => Err("rules must be an object or a list".to_string()),
+    }
+}
+
+fn rules_from_layouts(layouts: &[(String, String)]) -> Vec<Rule> {
+    layouts
+        .iter()
+        .map(|(app, layout)| Rule::new(app, layout))
+        .collect()
+}
+
+/// Parses an `"alias": "target"` map. A target is anything a rule accepts as a layout,
+/// but not another alias.
+fn parse_aliases(object: &Map<String, Value>) -> Result<Vec<(String, String)>, String> {
+    let mut aliases: Vec<(String, String)> = Vec::new();
+    for (alias, target) in object {
+        let target = match target.as_str() {
+            Some(target) if !target.trim().is_empty() => target.to_string(),
+            _ => return Err(format!("alias '{}' must name a layout", alias)),
+        };
+        if alias.trim().is_empty() {
+            return Err("alias names cannot be empty".to_string());
+        }
+        if aliases
+            .iter()
+            .any(|(known, _)| known.eq_ignore_ascii_case(alias))
+        {
+            return Err(format!("alias '{}' is defined more than once", alias));
+        }
+        aliases.push((alias.clone(), target));
+    }
+
+    for (alias, target) in &aliases {
+        if aliases
+            .iter()
+            .any(|(other, _)| other.eq_ignore_ascii_case(target))
+        {
+            return Err(format!(
+                "alias '{}' refers to the alias '{}'; aliases must name a layout",
+                alias, target
+            ));
+        }
+    }
+    Ok(aliases)
+}
+
+/// The top-level keys of the extended config format.
+const OPTIONS: &[&str] = &[
+    "rules",
+    "memory",
+    "default",
+    "exclude",
+    "overlays",
+    "grace_period",
+    "dwell_ms",
+    "project_files",
+    "layouts",
+    "aliases",
+    "enable_missing_layouts",
+    "profiles",
+    "profile",
+    "on_switch_failure",
+];
+
+/// Parses either the plain `"App": "LAYOUT"` map or the extended format
+/// with a `rules` object next to engine options.
+pub fn parse_config(content: &str) -> Result<Config, String> {
+    let value: Value = serde_json::from_str(content).map_err(|e| e.to_string())?;
+    let object = value
+        .as_object()
+        .ok_or_else(|| "config must be a JSON object".to_string())?;
+
+    let rules_value = match object.get("rules") {
+        Some(rules) => rules,
+        None => {
+            // Without a rules section every key is an application, so an option here
+            // would silently become a rule.
+            if let Some(option) = object.keys().find(|key| OPTIONS.contains(&key.as_str())) {
+                return Err(format!(
+                    "'{}' is an option, so the rules have to go in a \"rules\" section",
+                    option
+                ));
+            }
+            return parse_rule_map(object).map(Config::with_rules);
+        }
+    };
+
+    let mut config = Config::with_rules(parse_rules(rules_value)?);
+    for key in object.keys() {
+        if !OPTIONS.contains(&key.as_str()) {
+            config.warnings.push(format!("unknown option '{}'", key));
+        }
+    }
+
+    if let Some(memory) = object.get("memory") {
+        config.memory = memory
+            .as_str()
+            .and_then(MemoryMode::parse)
+            .ok_or_else(|| "memory must be one of \"off\", \"app\" or \"window\"".to_string())?;
+    }
+
+    match object.get("default") {
+        None | Some(Value::Null) => {}
+        Some(Value::String(layout)) if layout.eq_ignore_ascii_case("keep") => {}
+        Some(Value::String(layout)) => config.default_layout = Some(layout.clone()),
+        Some(_) => return Err("default must be a layout string or \"keep\"".to_string()),
+    }
+
+    if let Some(exclude) = object.get("exclude") {
+        let apps = exclude
+            .as_array()
+            .ok_or_else(|| "exclude must be a list of application names".to_string())?;
+        for app in apps {
+            match app.as_str() {
+                Some(app) => config.exclude.push(app.to_string()),
+                None => return Err("exclude must be a list of application names".to_string()),
+            }
+        }
+    }
+
+    if let Some(overlays) = object.get("overlays") {
+        config.overlays = parse_string_list("overlays", overlays)?;
+    }
+
+    match object.get("grace_period") {
+        None | Some(Value::Null) => {}
+        Some(value) => match value.as_f64() {
+            Some(seconds) if seconds >= 0.0 => {
+                config.grace_period = Some(Duration::from_secs_f64(seconds))
+            }
+            _ => return Err("grace_period must be a non-negative number of seconds".to_string()),
+        },
+    }
+
+    match object.get("dwell_ms") {
+        None | Some(Value::Null) => {}
+        Some(value) => match value.as_u64() {
+            Some(0) => {}
+            Some(millis) => config.dwell = Some(Duration::from_millis(millis)),
+            None => {
+                return Err("dwell_ms must be a non-negative number of milliseconds".to_string())
+            }
+        },
+    }
+
+    match object.get("project_files") {
+        None | Some(Value::Null) | Some(Value::Bool(false)) => {}
+        Some(Value::Bool(true)) => config.project_files = ProjectFiles::AllApps,
+        Some(apps) => {
+            config.project_files = ProjectFiles::Apps(parse_string_list("project_files", apps)?);
+        }
+    }
+
+    match object.get("layouts") {
+        None | Some(Value::Null) => {}
+        Some(Value::Object(definitions)) => {
+            config.layouts.extend(layouts::parse_layouts(definitions)?)
+        }
+        Some(_) => return Err("layouts must be an object of layout definitions".to_string()),
+    }
+
+    match object.get("aliases") {
+        None | Some(Value::Null) => {}
+        Some(Value::Object(aliases)) => config.aliases = parse_aliases(aliases)?,
+        Some(_) => return Err("aliases must be an object of alias names and layouts".to_string()),
+    }
+
+    match object.get("enable_missing_layouts") {
+        None | Some(Value::Null) => {}
+        Some(Value::Bool(enable)) => config.enable_missing_layouts = *enable,
+        Some(_) => return Err("enable_missing_layouts must be true or false".to_string()),
+    }
+
+    match object.get("profiles") {
+        None | Some(Value::Null) => {}
+        Some(Value::Object(profiles)) => {
+            for (name, rules) in profiles {
+                let rules = parse_rules(rules).map_err(|e| format!("profile '{}': {}", name, e))?;
+                config.profiles.push((name.clone(), rules));
+            }
+        }
+        Some(_) => return Err("profiles must be an object of named rule sets".to_string()),
+    }
+
+    match object.get("profile") {
+        None | Some(Value::Null) => {}
+        Some(Value::String(name)) => config.set_profile(Some(name))?,
+        Some(_) => return Err("profile must be the name of a profile".to_string()),
+    }
+
+    match object.get("on_switch_failure") {
+        None | Some(Value::Null) => {}
+        Some(Value::String(command)) => config.on_switch_failure = Some(command.clone()),
+        Some(_) => return Err("on_switch_failure must be a shell command string".to_string()),
+    }
+
+    Ok(config)
+}
+
+/// The config used when there is no config file: the default rules and nothing else.
+pub fn read_default_config() -> Config {
+    Config::with_rules(rules_from_layouts(&create_default_config()))
+}
+
+/// Reads the config file without creating or reporting anything, for commands that
+/// only look at it. A missing file gives the default rules.
+pub fn read_config() -> Result<Config, String> {
+    let config_path = get_config_path();
+    if !config_path.exists() {
+        return Ok(read_default_config());
+    }
+
+    let content = fs::read_to_string(&config_path)
+        .map_err(|e| format!("could not read {}: {}", config_path.display(), e))?;
+    parse_config(&content).map_err(|e| format!("{}: {}", config_path.display(), e))
 }
 
-pub fn load_or_create_config() -> HashMap<String, String> {
+pub fn load_or_create_config() -> Config {
     let config_path = get_config_path();
 
     if let Some(parent) = config_path.parent() {
         if !parent.exists() {
             if let Err(e) = fs::create_dir_all(parent) {
                 println!("Warning: Could not create config directory: {}", e);
-                return create_default_config();
+                return Config::with_rules(rules_from_layouts(&create_default_config()));
             }
         }
     }
 
     if config_path.exists() {
         match fs::read_to_string(&config_path) {
-            Ok(content) => match serde_json::from_str::<HashMap<String, String>>(&content) {
+            Ok(content) => match parse_config(&content) {
                 Ok(config) => {
                     println!("Loaded configuration from: {}", config_path.display());
+                    for warning in &config.warnings {
+                        println!("Warning: {}", warning);
+                    }
                     println!("Switching rules:");
-                    for (app, layout) in &config {
-                        println!("  {} -> {}", app, layout);
+                    for rule in &config.rules {
+                        println!("  {}", rule);
+                    }
+                    println!("Layout memory: {}", config.memory.as_str());
+                    println!(
+                        "Default layout: {}",
+                        config.default_layout.as_deref().unwrap_or("keep current")
+                    );
+                    match config.project_files {
+                        ProjectFiles::Off => {}
+                        ProjectFiles::AllApps => println!("Project files: all applications"),
+                        ProjectFiles::Apps(ref apps) => {
+                            println!("Project files: {}", apps.join(", "))
+                        }
+                    }
+                    if !config.exclude.is_empty() {
+                        println!("Excluded applications: {}", config.exclude.join(", "));
+                    }
+                    if let Some(dwell) = config.dwell {
+                        println!("Dwell time: {} ms", dwell.as_millis());
+                    }
+                    if !config.overlays.is_empty() {
+                        println!("Overlay applications: {}", config.overlays.join(", "));
+                    }
+                    if config.enable_missing_layouts {
+                        println!("Missing layouts: enabled automatically");
+                    }
+                    if !config.profiles.is_empty() {
+                        let names: Vec<&str> = config
+                            .profiles
+                            .iter()
+                            .map(|(name, _)| name.as_str())
+                            .collect();
+                        println!(
+                            "Profiles: {} (active: {})",
+                            names.join(", "),
+                            config.profile.as_deref().unwrap_or("none")
+                        );
+                    }
+                    if !config.aliases.is_empty() {
+                        println!("Layout aliases:");
+                        for (alias, target) in &config.aliases {
+                            println!("  {} = {}", alias, target);
+                        }
+                    }
+                    if config.rules.iter().any(Rule::has_window_condition)
+                        && !accessibility::is_trusted()
+                    {
+                        println!(
+                            "Warning: rules with a window type need Accessibility access \
+                             (System Settings > Privacy & Security > Accessibility)"
+                        );
                     }
                     return config;
                 }
                 Err(e) => {
-                    println!("Error parsing JSON config: {}. Creating a default one.", e);
+                    println!(
+                        "Error in config {}: {}. Using the default rules until it is fixed.",
+                        config_path.display(),
+                        e
+                    );
                 }
             },
             Err(e) => {
-                println!("Error reading config file: {}. Creating a default one.", e);
+                println!(
+                    "Error reading config file {}: {}. Using the default rules.",
+                    config_path.display(),
+                    e
+                );
             }
         }
+        // The file is the user's to fix; it is only written when there is none.
+        return read_default_config();
     }
 
     let default_config = create_default_config();
 
-    match serde_json::to_string_pretty(&default_config) {
-        Ok(json_content) => {
-            if let Err(e) = fs::write(&config_path, json_content) {
-                println!("Warning: Could not save default config: {}", e);
-            } else {
-                println!(
-                    "Created default configuration file at: {}",
-                    config_path.display()
-                );
-                println!("Default switching rules:");
-                for (app, layout) in &default_config {
-                    println!("  {} -> {}", app, layout);
-                }
+    if let Err(e) = write_replacing(&config_path, &default_config_text()) {
+        println!("Warning: Could not save default config: {}", e);
+    } else {
+        println!(
+            "Created default configuration file at: {}",
+            config_path.display()
+        );
+        println!("Default switching rules:");
+        for (app, layout) in &default_config {
+            println!("  {} -> {}", app, layout);
+        }
+    }
+
+    Config::with_rules(rules_from_layouts(&default_config))
+}
+
+/// Applies `edit` to the text of the config file and writes the result back, provided
+/// it still loads. Only the parts `edit` changes are touched. A missing file starts
+/// from the default rules.
+fn edit_config<T>(
+    edit: impl FnOnce(&str, &Node) -> Result<(String, T), String>,
+) -> Result<T, String> {
+    let config_path = get_config_path();
+    let content = if config_path.exists() {
+        fs::read_to_string(&config_path)
+            .map_err(|e| format!("could not read {}: {}", config_path.display(), e))?
+    } else {
+        default_config_text()
+    };
+
+    let (content, result) = edit_text(&content, edit)?;
+    if let Some(parent) = config_path.parent() {
+        fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+    }
+    write_replacing(&config_path, &content)
+        .map_err(|e| format!("could not write {}: {}", config_path.display(), e))?;
+    Ok(result)
+}
+
+/// Applies `edit` to the config text `content`, provided the result still loads.
+fn edit_text<T>(
+    content: &str,
+    edit: impl FnOnce(&str, &Node) -> Result<(String, T), String>,
+) -> Result<(String, T), String> {
+    let root = json_edit::parse(content)?;
+    if !matches!(root, Node::Object { .. }) {
+        return Err("config must be a JSON object".to_string());
+    }
+    let (content, result) = edit(content, &root)?;
+    parse_config(&content)?;
+    Ok((content, result))
+}
+
+/// Where the rules are: the `rules` section of the extended format, or the top level
+/// of the plain one.
+fn rules_node(root: &Node) -> &Node {
+    match root.member("rules") {
+        Some(member) if !matches!(member.value, Node::Scalar(_)) => &member.value,
+        _ => root,
+    }
+}
+
+/// Rules in `rules` written for exactly `app`, by position.
+fn rules_for_app(text: &str, rules: &Node, app: &str) -> Vec<usize> {
+    match rules {
+        Node::Object { members, .. } => members
+            .iter()
+            .enumerate()
+            .filter(|(_, member)| member.key == app)
+            .map(|(index, _)| index)
+            .collect(),
+        Node::Array { items, .. } => items
+            .iter()
+            .enumerate()
+            .filter(|(_, item)| {
+                item.member("app")
+                    .and_then(|member| member.value.as_str(text))
+                    .as_deref()
+                    == Some(app)
+            })
+            .map(|(index, _)| index)
+            .collect(),
+        Node::Scalar(_) => Vec::new(),
+    }
+}
+
+/// `"RU"` for one layout, `["RU", "EN"]` for several.
+fn layout_json(layouts: &[String]) -> String {
+    let quoted: Vec<String> = layouts
+        .iter()
+        .map(|layout| Value::String(layout.clone()).to_string())
+        .collect();
+    match quoted.as_slice() {
+        [layout] => layout.clone(),
+        _ => format!("[{}]", quoted.join(", ")),
+    }
+}
+
+/// Adds a rule switching `app` to `layouts`. Fails if `app` already has a rule.
+pub fn add_rule(app: &str, layouts: &[String]) -> Result<(), String> {
+    edit_config(|text, root| add_rule_in(text, root, app, layouts))
+}
+
+fn add_rule_in(
+    text: &str,
+    root: &Node,
+    app: &str,
+    layouts: &[String],
+) -> Result<(String, ()), String> {
+    let rules = rules_node(root);
+    if !rules_for_app(text, rules, app).is_empty() {
+        return Err(format!(
+            "there already is a rule for '{}'; use `rule set` to change it",
+            app
+        ));
+    }
+    Ok((append_rule(text, rules, app, layouts), ()))
+}
+
+/// Makes the rules for `app` switch to `layouts`, keeping their conditions, or adds
+/// a rule if there is none. Returns how many rules were changed.
+pub fn set_rule(app: &str, layouts: &[String]) -> Result<usize, String> {
+    edit_config(|text, root| set_rule_in(text, root, app, layouts))
+}
+
+fn set_rule_in(
+    text: &str,
+    root: &Node,
+    app: &str,
+    layouts: &[String],
+) -> Result<(String, usize), String> {
+    let rules = rules_node(root);
+    let indices = rules_for_app(text, rules, app);
+    if indices.is_empty() {
+        return Ok((append_rule(text, rules, app, layouts), 0));
+    }
+
+    let entries: Vec<&Node> = match rules {
+        Node::Object { members, .. } => members.iter().map(|member| &member.value).collect(),
+        Node::Array { items, .. } => items.iter().collect(),
+        Node::Scalar(_) => Vec::new(),
+    };
+    let layout = layout_json(layouts);
+    // From the last to the first, so that earlier positions stay valid.
+    let mut content = text.to_string();
+    for &index in indices.iter().rev() {
+        let rule = entries[index];
+        content = match (rule, rule.member("layout")) {
+            (Node::Object { .. }, Some(member)) => {
+                json_edit::replace(&content, &member.value, &layout)
             }
+            (Node::Object { .. }, None) => {
+                json_edit::insert_member(&content, rule, "layout", &layout)
+            }
+            _ => json_edit::replace(&content, rule, &layout),
+        };
+    }
+    Ok((content, indices.len()))
+}
+
+fn append_rule(text: &str, rules: &Node, app: &str, layouts: &[String]) -> String {
+    let app_json = Value::String(app.to_string()).to_string();
+    match rules {
+        Node::Array { .. } => {
+            let rule = format!(
+                "{{ \"app\": {}, \"layout\": {} }}",
+                app_json,
+                layout_json(layouts)
+            );
+            json_edit::push_item(text, rules, &rule)
         }
-        Err(e) => {
-            println!("Error serializing default config: {}", e);
+        _ => json_edit::insert_member(text, rules, app, &layout_json(layouts)),
+    }
+}
+
+/// Removes the rules for `app` and returns how many there were.
+pub fn remove_rule(app: &str) -> Result<usize, String> {
+    edit_config(|text, root| remove_rule_in(text, root, app))
+}
+
+fn remove_rule_in(text: &str, root: &Node, app: &str) -> Result<(String, usize), String> {
+    let rules = rules_node(root);
+    let indices = rules_for_app(text, rules, app);
+    if indices.is_empty() {
+        return Err(format!("there is no rule for '{}'", app));
+    }
+
+    // One at a time, reading the text again after each removal.
+    let mut content = json_edit::remove_entry(text, rules, indices[indices.len() - 1]);
+    loop {
+        let root = json_edit::parse(&content)?;
+        let rules = rules_node(&root);
+        match rules_for_app(&content, rules, app).last() {
+            Some(&index) => content = json_edit::remove_entry(&content, rules, index),
+            None => break,
         }
     }
+    Ok((content, indices.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn apps(config: &Config) -> Vec<&str> {
+        config
+            .rules
+            .iter()
+            .filter_map(|rule| rule.app.as_deref())
+            .collect()
+    }
+
+    #[test]
+    fn plain_map_is_a_list_of_rules() {
+        let config = parse_config(r#"{ "Alacritty": "EN", "Telegram": "RU" }"#).unwrap();
+        assert_eq!(apps(&config), ["Alacritty", "Telegram"]);
+        assert_eq!(config.rules[1].layouts, ["RU"]);
+        assert!(config.warnings.is_empty());
+    }
+
+    #[test]
+    fn extended_format_reads_options() {
+        let config =
+            parse_config(r#"{ "rules": { "Terminal": "EN" }, "memory": "app", "default": "RU" }"#)
+                .unwrap();
+        assert_eq!(apps(&config), ["Terminal"]);
+        assert_eq!(config.memory, MemoryMode::App);
+        assert_eq!(config.default_layout.as_deref(), Some("RU"));
+    }
+
+    #[test]
+    fn all_rules_include_inactive_profiles() {
+        let config = parse_config(
+            r#"{ "rules": { "Terminal": "EN" }, "profiles": { "home": { "Telegram": "RU" } } }"#,
+        )
+        .unwrap();
+        assert_eq!(apps(&config), ["Terminal"]);
+        let all: Vec<_> = config
+            .all_rules()
+            .filter_map(|rule| rule.app.as_deref())
+            .collect();
+        assert_eq!(all, ["Terminal", "Telegram"]);
+    }
+
+    #[test]
+    fn options_without_rules_section_are_rejected() {
+        let error = parse_config(r#"{ "memory": "app", "default": "EN" }"#).unwrap_err();
+        assert!(error.contains("\"rules\" section"), "{}", error);
+    }
 
-    default_config
+    #[test]
+    fn rules_must_be_an_object_or_list() {
+        assert!(parse_config(r#"{ "rules": "EN" }"#).is_err());
+    }
+
+    #[test]
+    fn unknown_options_are_warnings() {
+        let config = parse_config(r#"{ "rules": {}, "memroy": "app" }"#).unwrap();
+        assert_eq!(config.warnings, ["unknown option 'memroy'"]);
+    }
+
+    #[test]
+    fn default_config_keeps_its_order() {
+        let text = default_config_text();
+        assert_eq!(parse_config(&text).unwrap().rules.len(), 6);
+        let keys: Vec<String> = match json_edit::parse(&text).unwrap() {
+            Node::Object { members, .. } => members.into_iter().map(|member| member.key).collect(),
+            _ => Vec::new(),
+        };
+        let expected: Vec<String> = create_default_config()
+            .into_iter()
+            .map(|(app, _)| app)
+            .collect();
+        assert_eq!(keys, expected);
+    }
+
+    #[test]
+    fn config_file_is_replaced_as_a_whole() {
+        use std::os::unix::fs::{symlink, PermissionsExt};
+
+        let dir =
+            std::env::temp_dir().join(format!("language-handler-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("config.json");
+        let link = dir.join("link.json");
+        fs::write(&file, "{}").unwrap();
+        fs::set_permissions(&file, fs::Permissions::from_mode(0o600)).unwrap();
+        let _ = fs::remove_file(&link);
+        symlink(&file, &link).unwrap();
+
+        write_replacing(&link, "{ \"Xcode\": \"US\" }").unwrap();
+        assert!(fs::symlink_metadata(&link)
+            .unwrap()
+            .file_type()
+            .is_symlink());
+        assert_eq!(fs::read_to_string(&file).unwrap(), "{ \"Xcode\": \"US\" }");
+        assert_eq!(
+            fs::metadata(&file).unwrap().permissions().mode() & 0o777,
+            0o600
+        );
+        assert!(!dir.join("config.json.tmp").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn layouts(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    fn set(text: &str, app: &str, names: &[&str]) -> (String, usize) {
+        edit_text(text, |text, root| {
+            set_rule_in(text, root, app, &layouts(names))
+        })
+        .unwrap()
+    }
+
+    fn remove(text: &str, app: &str) -> Result<(String, usize), String> {
+        edit_text(text, |text, root| remove_rule_in(text, root, app))
+    }
+
+    #[test]
+    fn set_rule_replaces_plain_rule() {
+        let text = "{\n  \"Alacritty\": \"EN\",\n  \"Telegram\": \"RU\"\n}";
+        let (content, changed) = set(text, "Telegram", &["EN", "RU"]);
+        assert_eq!(changed, 1);
+        assert_eq!(
+            content,
+            "{\n  \"Alacritty\": \"EN\",\n  \"Telegram\": [\"EN\", \"RU\"]\n}"
+        );
+    }
+
+    #[test]
+    fn set_rule_keeps_conditions() {
+        let text = r#"{ "rules": [{ "app": "Terminal", "args": "vim", "layout": "RU" }] }"#;
+        let (content, changed) = set(text, "Terminal", &["EN"]);
+        assert_eq!(changed, 1);
+        assert_eq!(
+            content,
+            r#"{ "rules": [{ "app": "Terminal", "args": "vim", "layout": "EN" }] }"#
+        );
+    }
+
+    #[test]
+    fn set_rule_changes_every_rule_for_the_app() {
+        let text = r#"{ "rules": [{ "app": "Terminal", "args": "vim", "layout": "RU" }, { "app": "Terminal", "layout": "DE" }] }"#;
+        let (content, changed) = set(text, "Terminal", &["EN"]);
+        assert_eq!(changed, 2);
+        let config = parse_config(&content).unwrap();
+        assert!(config.rules.iter().all(|rule| rule.layouts == ["EN"]));
+        assert_eq!(config.rules[0].args.as_deref(), Some("vim"));
+    }
+
+    #[test]
+    fn set_rule_adds_missing_rule() {
+        let (content, changed) = set(r#"{ "rules": {} }"#, "Telegram", &["RU"]);
+        assert_eq!(changed, 0);
+        assert_eq!(apps(&parse_config(&content).unwrap()), ["Telegram"]);
+    }
+
+    #[test]
+    fn remove_rule_keeps_other_rules() {
+        let text = "{\n  \"Alacritty\": \"EN\",\n  \"Telegram\": \"RU\"\n}";
+        let (content, removed) = remove(text, "Alacritty").unwrap();
+        assert_eq!(removed, 1);
+        assert_eq!(content, "{\n  \"Telegram\": \"RU\"\n}");
+    }
+
+    #[test]
+    fn remove_rule_removes_every_rule_for_the_app() {
+        let text = r#"{ "rules": [{ "app": "Terminal", "layout": "RU" }, { "app": "Safari", "layout": "EN" }, { "app": "Terminal", "layout": "DE" }] }"#;
+        let (content, removed) = remove(text, "Terminal").unwrap();
+        assert_eq!(removed, 2);
+        assert_eq!(
+            content,
+            r#"{ "rules": [{ "app": "Safari", "layout": "EN" }] }"#
+        );
+    }
+
+    #[test]
+    fn remove_rule_fails_without_a_rule() {
+        let error = remove(r#"{ "Alacritty": "EN" }"#, "Telegram").unwrap_err();
+        assert_eq!(error, "there is no rule for 'Telegram'");
+    }
 }
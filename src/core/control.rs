@@ -0,0 +1,536 @@
+use crate::core::macos_api::umask;
+use crate::core::{config, monitor, observer, sources, switcher};
+use crate::state;
+
+use cocoa::base::{id, nil, NO};
+use cocoa::foundation::NSAutoreleasePool;
+use objc::{msg_send, sel, sel_impl};
+use serde_json::{json, Value};
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::net::Shutdown;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::mpsc::{self, Sender, SyncSender};
+use std::sync::{Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, UNIX_EPOCH};
+
+/// How long a connection waits for the main thread to handle a request.
+pub const REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+/// How many lines may wait to be written to a connection. A subscriber whose queue is
+/// full is dropped rather than waited for.
+const WRITE_QUEUE_LEN: usize = 64;
+/// How long writing to a connection may block before it is closed.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(2);
+
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+/// The request was understood but could not be carried out.
+const REQUEST_FAILED: i64 = -32000;
+
+/// A JSON-RPC error object.
+#[derive(Debug)]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+}
+
+impl RpcError {
+    fn new(code: i64, message: impl Into<String>) -> RpcError {
+        RpcError {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+/// A request waiting for the main thread, with the channel its connection waits on.
+struct Call {
+    method: String,
+    params: Value,
+    reply: Sender<Result<Value, RpcError>>,
+}
+
+/// Requests handed from connection threads to the main thread.
+static CALLS: Mutex<Vec<Call>> = Mutex::new(Vec::new());
+/// Write queues of the connections that asked for events.
+static SUBSCRIBERS: Mutex<Vec<SyncSender<String>>> = Mutex::new(Vec::new());
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Opens the control socket, readable and writable by the user only, and accepts
+/// connections on a background thread. Requests are carried out on the main thread
+/// through the observer, like notifications. If the socket cannot be kept to the user,
+/// there is no control socket.
+///
+/// # Safety
+///
+/// This function is unsafe because it reads `state::OBSERVER` and changes the process
+/// umask while it creates the socket. It must be called after
+/// `observer::setup_observers`, before other threads create files.
+pub unsafe fn start() {
+    let observer = match state::OBSERVER {
+        Some(observer) => observer as usize,
+        None => return,
+    };
+
+    let socket_path = config::get_socket_path();
+    if UnixStream::connect(&socket_path).is_ok() {
+        println!(
+            "Warning: another instance is listening on {}, control socket disabled",
+            socket_path.display()
+        );
+        return;
+    }
+    let _ = fs::remove_file(&socket_path);
+
+    // Without group and other access from the moment it exists, not just after the
+    // permissions are set below.
+    let previous_mask = umask(0o077);
+    let bound = UnixListener::bind(&socket_path);
+    umask(previous_mask);
+    let listener = match bound {
+        Ok(listener) => listener,
+        Err(e) => {
+            println!(
+                "Warning: could not open the control socket {}: {}",
+                socket_path.display(),
+                e
+            );
+            return;
+        }
+    };
+    if let Err(e) = fs::set_permissions(&socket_path, fs::Permissions::from_mode(0o600)) {
+        println!(
+            "Warning: could not restrict the control socket to this user: {}, control socket disabled",
+            e
+        );
+        drop(listener);
+        let _ = fs::remove_file(&socket_path);
+        return;
+    }
+    println!("Control socket: {}", socket_path.display());
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    thread::spawn(move || serve(stream, observer));
+                }
+                Err(e) => println!("Control connection failed: {}", e),
+            }
+        }
+    });
+}
+
+/// Answers the requests of one connection, one JSON object per line. After
+/// `subscribe`, events are written to the connection as well.
+fn serve(stream: UnixStream, observer: usize) {
+    let reader = match stream.try_clone() {
+        Ok(reader) => BufReader::new(reader),
+        Err(_) => return,
+    };
+    let writer = match start_writer(stream) {
+        Some(writer) => writer,
+        None => return,
+    };
+    let mut subscribed = false;
+
+    for line in reader.lines() {
+        let line = match line {
+            Ok(line) if line.trim().is_empty() => continue,
+            Ok(line) => line,
+            Err(_) => break,
+        };
+
+        let mut subscribe = false;
+        let (request_id, request) = read_request(&line);
+        let result = request.and_then(|(method, params)| {
+            if method == "subscribe" {
+                subscribe = !subscribed;
+                Ok(json!({ "subscribed": true }))
+            } else {
+                call(&method, params, observer)
+            }
+        });
+
+        // Requests without an id are notifications and get no response.
+        if let Some(request_id) = request_id {
+            let response = match result {
+                Ok(result) => json!({ "jsonrpc": "2.0", "id": request_id, "result": result }),
+                Err(error) => json!({
+                    "jsonrpc": "2.0",
+                    "id": request_id,
+                    "error": { "code": error.code, "message": error.message },
+                }),
+            };
+            if writer.send(format!("{}\n", response)).is_err() {
+                break;
+            }
+        }
+
+        // Only after the reply is queued, so that no event can get ahead of it.
+        if subscribe {
+            lock(&SUBSCRIBERS).push(writer.clone());
+            subscribed = true;
+        }
+    }
+}
+
+/// Takes a request line apart into its id, if it has one, and its method and params.
+/// Lines that are not a request get an error with a null id, so that they are
+/// answered rather than taken for notifications.
+fn read_request(line: &str) -> (Option<Value>, Result<(String, Value), RpcError>) {
+    let request = match serde_json::from_str::<Value>(line) {
+        Ok(Value::Object(request)) => request,
+        Ok(Value::Array(_)) => {
+            return (
+                Some(Value::Null),
+                Err(RpcError::new(
+                    INVALID_REQUEST,
+                    "batch requests are not supported",
+                )),
+            )
+        }
+        Ok(_) => {
+            return (
+                Some(Value::Null),
+                Err(RpcError::new(INVALID_REQUEST, "request must be an object")),
+            )
+        }
+        Err(e) => {
+            return (
+                Some(Value::Null),
+                Err(RpcError::new(PARSE_ERROR, e.to_string())),
+            )
+        }
+    };
+
+    let request_id = request.get("id").cloned();
+    match request.get("method").and_then(Value::as_str) {
+        Some(method) => {
+            let params = request.get("params").cloned().unwrap_or(Value::Null);
+            (request_id, Ok((method.to_string(), params)))
+        }
+        None => (
+            Some(request_id.unwrap_or(Value::Null)),
+            Err(RpcError::new(INVALID_REQUEST, "request has no method")),
+        ),
+    }
+}
+
+/// Starts a thread that writes the lines sent to the returned queue to `stream`, so
+/// that replies and events never interleave and nobody else waits on a slow client.
+fn start_writer(mut stream: UnixStream) -> Option<SyncSender<String>> {
+    stream.set_write_timeout(Some(WRITE_TIMEOUT)).ok()?;
+    let (writer, lines) = mpsc::sync_channel::<String>(WRITE_QUEUE_LEN);
+    thread::spawn(move || {
+        for line in lines {
+            if stream.write_all(line.as_bytes()).is_err() {
+                // Ends the reading side as well, so the connection is closed.
+                let _ = stream.shutdown(Shutdown::Both);
+                break;
+            }
+        }
+    });
+    Some(writer)
+}
+
+/// Queues a request for the main thread, wakes it up and waits for the result.
+fn call(method: &str, params: Value, observer: usize) -> Result<Value, RpcError> {
+    let (reply, response) = mpsc::channel();
+    lock(&CALLS).push(Call {
+        method: method.to_string(),
+        params,
+        reply,
+    });
+
+    unsafe {
+        let _pool = NSAutoreleasePool::new(nil);
+        let observer = observer as id;
+        let _: () = msg_send![observer,
+            performSelectorOnMainThread: sel!(controlRequest:)
+            withObject: nil
+            waitUntilDone: NO
+        ];
+    }
+
+    response
+        .recv_timeout(REPLY_TIMEOUT)
+        .unwrap_or_else(|_| Err(RpcError::new(REQUEST_FAILED, "no answer in time")))
+}
+
+/// Carries out the queued requests. Called on the main thread.
+///
+/// # Safety
+///
+/// This function is unsafe because the requests read and write `static mut`
+/// variables in `state` and may switch the layout.
+pub unsafe fn handle_calls() {
+    let calls = std::mem::take(&mut *lock(&CALLS));
+    for call in calls {
+        let result = dispatch(&call.method, &call.params);
+        let _ = call.reply.send(result);
+    }
+}
+
+unsafe fn dispatch(method: &str, params: &Value) -> Result<Value, RpcError> {
+    match method {
+        "status" => Ok(status()),
+        "list_rules" => Ok(list_rules()),
+        "pause" => Ok(set_paused(true)),
+        "resume" => Ok(set_paused(false)),
+        "reload" => reload(),
+        "switch" => switch(params),
+        "set_profile" => set_profile(params),
+        _ => Err(RpcError::new(
+            METHOD_NOT_FOUND,
+            format!("unknown method '{}'", method),
+        )),
+    }
+}
+
+unsafe fn status() -> Value {
+    let paused = state::PAUSED;
+    let config = (*std::ptr::addr_of!(state::CONFIG)).as_ref();
+    let last_failure = (*std::ptr::addr_of!(state::LAST_SWITCH_FAILURE))
+        .as_ref()
+        .map(|failure| {
+            json!({
+                "target": failure.target.to_string(),
+                "outcome": failure.outcome.as_str(),
+                "message": failure.outcome.to_string(),
+                "app": failure.app,
+                "attempts": failure.attempts,
+                "at": failure.at.duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs()),
+            })
+        });
+
+    json!({
+        "running": true,
+        "pid": std::process::id(),
+        "paused": paused,
+        "app": *std::ptr::addr_of!(state::CURRENT_APP),
+        "layout": *std::ptr::addr_of!(state::CURRENT_KEYBOARD_LAYOUT),
+        "input_source": *std::ptr::addr_of!(state::CURRENT_INPUT_SOURCE_ID),
+        "profile": config.and_then(|config| config.profile.clone()),
+        "rules": config.map_or(0, |config| config.rules.len()),
+        "config": config::get_config_path().display().to_string(),
+        "last_failure": last_failure,
+    })
+}
+
+unsafe fn list_rules() -> Value {
+    (*std::ptr::addr_of!(state::CONFIG))
+        .as_ref()
+        .map(|config| {
+            config
+                .rules
+                .iter()
+                .map(|rule| rule.to_string())
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default()
+        .into()
+}
+
+unsafe fn set_paused(paused: bool) -> Value {
+    if state::PAUSED != paused {
+        state::PAUSED = paused;
+        if paused {
+            println!("Paused, layouts are left alone until resumed");
+            emit("paused", json!({}));
+        } else {
+            println!("Resumed");
+            emit("resumed", json!({}));
+            switcher::check_and_switch_layout_by_rules();
+        }
+    }
+    json!(if paused { "Paused" } else { "Resumed" })
+}
+
+/// Reads the config file again. The active profile is kept if it still exists; a
+/// config that does not load leaves the current one in place.
+unsafe fn reload() -> Result<Value, RpcError> {
+    let mut config = config::read_config().map_err(|e| RpcError::new(REQUEST_FAILED, e))?;
+    if let Some(profile) = (*std::ptr::addr_of!(state::CONFIG))
+        .as_ref()
+        .and_then(|config| config.profile.clone())
+    {
+        if config.set_profile(Some(&profile)).is_err() {
+            println!("Profile '{}' no longer exists", profile);
+        }
+    }
+
+    let rule_count = config.rules.len();
+    state::CONFIG = Some(config);
+    sources::invalidate();
+    println!(
+        "Reloaded configuration from: {}",
+        config::get_config_path().display()
+    );
+    if let Some(config) = (*std::ptr::addr_of!(state::CONFIG)).as_ref() {
+        for warning in &config.warnings {
+            println!("Warning: {}", warning);
+        }
+    }
+    switcher::check_aliases();
+    observer::update_watchers();
+    monitor::update_active_window();
+    switcher::check_and_switch_layout_by_rules();
+    emit("config_reloaded", json!({ "rules": rule_count }));
+
+    Ok(json!(format!("Reloaded {} rules", rule_count)))
+}
+
+unsafe fn switch(params: &Value) -> Result<Value, RpcError> {
+    let layout = params
+        .get("layout")
+        .and_then(Value::as_str)
+        .ok_or_else(|| RpcError::new(INVALID_PARAMS, "switch needs a \"layout\""))?;
+
+    let outcome = switcher::switch_to_layout(layout);
+    if outcome.is_failure() {
+        return Err(RpcError::new(
+            REQUEST_FAILED,
+            format!("could not switch to '{}': {}", layout, outcome),
+        ));
+    }
+    Ok(json!(outcome.to_string()))
+}
+
+unsafe fn set_profile(params: &Value) -> Result<Value, RpcError> {
+    let name = match params.get("profile") {
+        None | Some(Value::Null) => None,
+        Some(Value::String(name)) => Some(name.as_str()),
+        Some(_) => {
+            return Err(RpcError::new(
+                INVALID_PARAMS,
+                "profile must be a profile name or null",
+            ))
+        }
+    };
+
+    let config = (*std::ptr::addr_of_mut!(state::CONFIG))
+        .as_mut()
+        .ok_or_else(|| RpcError::new(REQUEST_FAILED, "no configuration loaded"))?;
+    config
+        .set_profile(name)
+        .map_err(|e| RpcError::new(INVALID_PARAMS, e))?;
+    let profile = config.profile.clone();
+
+    println!("Profile: {}", profile.as_deref().unwrap_or("none"));
+    observer::update_watchers();
+    monitor::update_active_window();
+    switcher::check_and_switch_layout_by_rules();
+    emit("profile_changed", json!({ "profile": profile }));
+    Ok(json!({ "profile": profile }))
+}
+
+/// Queues an event for every subscriber as a JSON-RPC notification, dropping the
+/// subscribers that are gone or too far behind. Never waits on a subscriber.
+pub fn emit(event: &str, fields: Value) {
+    let mut subscribers = lock(&SUBSCRIBERS);
+    if subscribers.is_empty() {
+        return;
+    }
+
+    let mut params = json!({ "type": event });
+    if let (Some(params), Value::Object(fields)) = (params.as_object_mut(), fields) {
+        params.extend(fields);
+    }
+    let notification = json!({ "jsonrpc": "2.0", "method": "event", "params": params });
+    let line = format!("{}\n", notification);
+    subscribers.retain(|subscriber| subscriber.try_send(line.clone()).is_ok());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_line(reader: &mut BufReader<UnixStream>) -> Value {
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        serde_json::from_str(&line).unwrap()
+    }
+
+    fn error_for(line: &str) -> (Option<Value>, i64) {
+        let (request_id, request) = read_request(line);
+        (request_id, request.unwrap_err().code)
+    }
+
+    #[test]
+    fn requests_are_taken_apart() {
+        let (request_id, request) =
+            read_request(r#"{"jsonrpc":"2.0","id":7,"method":"switch","params":{"layout":"RU"}}"#);
+        let (method, params) = request.unwrap();
+        assert_eq!(request_id, Some(json!(7)));
+        assert_eq!(method, "switch");
+        assert_eq!(params["layout"], "RU");
+
+        let (request_id, request) = read_request(r#"{"jsonrpc":"2.0","method":"pause"}"#);
+        assert_eq!(request_id, None);
+        assert_eq!(request.unwrap(), ("pause".to_string(), Value::Null));
+    }
+
+    #[test]
+    fn invalid_requests_are_answered_with_a_null_id() {
+        let invalid = (Some(Value::Null), INVALID_REQUEST);
+        assert_eq!(
+            error_for(r#"[{"jsonrpc":"2.0","id":1,"method":"status"}]"#),
+            invalid
+        );
+        assert_eq!(error_for("1"), invalid);
+        assert_eq!(error_for(r#""x""#), invalid);
+        assert_eq!(error_for(r#"{"jsonrpc":"2.0","method":5}"#), invalid);
+        assert_eq!(error_for(r#"{"jsonrpc":"2.0"}"#), invalid);
+        assert_eq!(error_for("{"), (Some(Value::Null), PARSE_ERROR));
+        assert_eq!(
+            error_for(r#"{"jsonrpc":"2.0","id":3}"#),
+            (Some(json!(3)), INVALID_REQUEST)
+        );
+    }
+
+    #[test]
+    fn batch_gets_an_error_response() {
+        let (mut client, server) = UnixStream::pair().unwrap();
+        thread::spawn(move || serve(server, 0));
+        let mut reader = BufReader::new(client.try_clone().unwrap());
+
+        client.write_all(b"[]\n").unwrap();
+        let response = read_line(&mut reader);
+        assert_eq!(response["id"], Value::Null);
+        assert_eq!(response["error"]["code"], INVALID_REQUEST);
+    }
+
+    #[test]
+    fn subscriber_gets_reply_before_events() {
+        let (mut client, server) = UnixStream::pair().unwrap();
+        thread::spawn(move || serve(server, 0));
+        let mut reader = BufReader::new(client.try_clone().unwrap());
+
+        client
+            .write_all(b"{\"jsonrpc\":\"2.0\",\"id\":1,\"method\":\"subscribe\"}\n")
+            .unwrap();
+        assert_eq!(read_line(&mut reader)["result"]["subscribed"], true);
+
+        // Answered by the same thread, so the first subscription is in place by now.
+        client
+            .write_all(b"{\"jsonrpc\":\"2.0\",\"id\":2,\"method\":\"subscribe\"}\n")
+            .unwrap();
+        assert_eq!(read_line(&mut reader)["id"], 2);
+
+        emit("paused", json!({}));
+        let event = read_line(&mut reader);
+        assert_eq!(event["method"], "event");
+        assert_eq!(event["params"]["type"], "paused");
+        assert_eq!(lock(&SUBSCRIBERS).len(), 1);
+    }
+}
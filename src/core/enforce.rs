@@ -0,0 +1,69 @@
+use std::time::{Duration, Instant};
+
+/// Number of times an enforced layout may be restored within `REVERT_WINDOW`.
+const MAX_REVERTS: usize = 3;
+const REVERT_WINDOW: Duration = Duration::from_secs(10);
+
+/// Limits how often an enforced rule switches the layout back, so that two tools
+/// changing the layout in turn stop instead of looping forever.
+#[derive(Debug, Default)]
+pub struct EnforcementGuard {
+    reverts: Vec<Instant>,
+    suspended: bool,
+}
+
+impl EnforcementGuard {
+    pub fn new() -> EnforcementGuard {
+        EnforcementGuard::default()
+    }
+
+    /// Records a revert. Returns `false`, and stays suspended, once there have been
+    /// too many of them in a short time.
+    pub fn allow_revert(&mut self) -> bool {
+        if self.suspended {
+            return false;
+        }
+
+        let now = Instant::now();
+        self.reverts
+            .retain(|at| now.duration_since(*at) < REVERT_WINDOW);
+        if self.reverts.len() >= MAX_REVERTS {
+            self.suspended = true;
+            return false;
+        }
+
+        self.reverts.push(now);
+        true
+    }
+
+    pub fn is_suspended(&self) -> bool {
+        self.suspended
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn repeated_reverts_suspend_enforcement() {
+        let mut guard = EnforcementGuard::new();
+        for _ in 0..MAX_REVERTS {
+            assert!(guard.allow_revert());
+        }
+        assert!(!guard.is_suspended());
+
+        assert!(!guard.allow_revert());
+        assert!(guard.is_suspended());
+        assert!(!guard.allow_revert());
+    }
+
+    #[test]
+    fn old_reverts_do_not_count() {
+        let mut guard = EnforcementGuard::new();
+        guard.reverts = vec![Instant::now() - REVERT_WINDOW - Duration::from_secs(1); MAX_REVERTS];
+        assert!(guard.allow_revert());
+        assert_eq!(guard.reverts.len(), 1);
+        assert!(!guard.is_suspended());
+    }
+}
@@ -0,0 +1,21 @@
+use std::process::Command;
+use std::thread;
+
+/// Runs a user-configured shell command without waiting for it. `env` is passed to
+/// the command as extra environment variables.
+pub fn run_hook(name: &str, command: &str, env: &[(&str, String)]) {
+    let mut child = Command::new("/bin/sh");
+    child.arg("-c").arg(command);
+    for (key, value) in env {
+        child.env(key, value);
+    }
+
+    match child.spawn() {
+        Ok(mut child) => {
+            thread::spawn(move || {
+                let _ = child.wait();
+            });
+        }
+        Err(e) => println!("Failed to run {} hook: {}", name, e),
+    }
+}
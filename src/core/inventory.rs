@@ -0,0 +1,135 @@
+use crate::core::resolve::MatchKind;
+use crate::core::sources::{InputSource, SourceCatalogue};
+use crate::core::switcher;
+use crate::state;
+
+use serde_json::{json, Value};
+
+/// An installed input source together with the config codes and aliases that
+/// resolve to it.
+pub struct SourceEntry<'a> {
+    pub source: &'a InputSource,
+    /// Codes and aliases, each with how it matched the source.
+    pub targets: Vec<(String, MatchKind)>,
+}
+
+/// Lists every input source in `catalogue`, in installation order.
+///
+/// # Safety
+///
+/// This function is unsafe because it reads `state::CONFIG`.
+pub unsafe fn list_sources(catalogue: &SourceCatalogue) -> Vec<SourceEntry<'_>> {
+    let mut entries: Vec<SourceEntry> = catalogue
+        .iter()
+        .map(|source| SourceEntry {
+            source,
+            targets: Vec::new(),
+        })
+        .collect();
+
+    let config = match (*std::ptr::addr_of!(state::CONFIG)).as_ref() {
+        Some(config) => config,
+        None => return entries,
+    };
+
+    let targets = config
+        .aliases
+        .iter()
+        .map(|(alias, _)| alias.clone())
+        .chain(config.layouts.iter().map(|layout| layout.code.clone()));
+    for target in targets {
+        for candidate in switcher::candidate_sources(catalogue, &target, true) {
+            if let Some(entry) = entries
+                .iter_mut()
+                .find(|entry| entry.source.id == candidate.source.id)
+            {
+                entry.targets.push((target.clone(), candidate.kind));
+            }
+        }
+    }
+    entries
+}
+
+fn yes_no(value: bool) -> &'static str {
+    if value {
+        "yes"
+    } else {
+        "no"
+    }
+}
+
+/// Prints the entries as a table, one input source per line.
+pub fn print_table(entries: &[SourceEntry]) {
+    let header = [
+        "ID",
+        "NAME",
+        "LANGUAGES",
+        "TYPE",
+        "ENABLED",
+        "SELECTABLE",
+        "CODES",
+    ];
+    let rows: Vec<[String; 7]> = entries
+        .iter()
+        .map(|entry| {
+            [
+                entry.source.id.clone(),
+                entry.source.name.clone(),
+                entry.source.languages.join(","),
+                entry.source.kind.as_str().to_string(),
+                yes_no(entry.source.enabled).to_string(),
+                yes_no(entry.source.selectable).to_string(),
+                entry
+                    .targets
+                    .iter()
+                    .map(|(target, _)| target.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", "),
+            ]
+        })
+        .collect();
+
+    let mut widths = header.map(str::len);
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    let print_row = |cells: &[&str]| {
+        let line: Vec<String> = cells
+            .iter()
+            .zip(widths)
+            .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+            .collect();
+        println!("{}", line.join("  ").trim_end());
+    };
+
+    print_row(&header);
+    for row in &rows {
+        print_row(&row.each_ref().map(String::as_str));
+    }
+}
+
+/// The entries as a JSON array.
+pub fn to_json(entries: &[SourceEntry]) -> Value {
+    entries
+        .iter()
+        .map(|entry| {
+            json!({
+                "id": entry.source.id,
+                "name": entry.source.name,
+                "languages": entry.source.languages,
+                "type": entry.source.kind.as_str(),
+                "input_mode": entry.source.input_mode,
+                "enabled": entry.source.enabled,
+                "selectable": entry.source.selectable,
+                "codes": entry
+                    .targets
+                    .iter()
+                    .map(|(target, kind)| json!({ "code": target, "match": kind.to_string() }))
+                    .collect::<Vec<_>>(),
+            })
+        })
+        .collect()
+}
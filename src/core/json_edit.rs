@@ -0,0 +1,407 @@
+use std::ops::Range;
+
+/// A JSON value located in the text it was parsed from, so that it can be replaced
+/// without touching the rest of the text.
+#[derive(Debug)]
+pub enum Node {
+    /// A string, number, boolean or null.
+    Scalar(Range<usize>),
+    Object {
+        span: Range<usize>,
+        members: Vec<Member>,
+    },
+    Array {
+        span: Range<usize>,
+        items: Vec<Node>,
+    },
+}
+
+#[derive(Debug)]
+pub struct Member {
+    pub key: String,
+    /// Where the quoted key starts.
+    pub start: usize,
+    pub value: Node,
+}
+
+impl Node {
+    pub fn span(&self) -> Range<usize> {
+        match self {
+            Node::Scalar(span) | Node::Object { span, .. } | Node::Array { span, .. } => {
+                span.clone()
+            }
+        }
+    }
+
+    pub fn member(&self, key: &str) -> Option<&Member> {
+        match self {
+            Node::Object { members, .. } => members.iter().find(|member| member.key == key),
+            _ => None,
+        }
+    }
+
+    /// The value of a string node.
+    pub fn as_str(&self, text: &str) -> Option<String> {
+        match self {
+            Node::Scalar(span) => serde_json::from_str(&text[span.clone()]).ok(),
+            _ => None,
+        }
+    }
+
+    /// Spans of the members or items, each from the key or value start to the value end.
+    fn entries(&self) -> Vec<Range<usize>> {
+        match self {
+            Node::Scalar(_) => Vec::new(),
+            Node::Object { members, .. } => members
+                .iter()
+                .map(|member| member.start..member.value.span().end)
+                .collect(),
+            Node::Array { items, .. } => items.iter().map(Node::span).collect(),
+        }
+    }
+}
+
+/// Parses `text` into nodes that remember where they are. Only standard JSON is
+/// accepted.
+pub fn parse(text: &str) -> Result<Node, String> {
+    let mut parser = Parser {
+        text,
+        bytes: text.as_bytes(),
+        pos: 0,
+    };
+    let node = parser.value()?;
+    parser.skip_whitespace();
+    if parser.pos != text.len() {
+        return Err(parser.error("trailing characters"));
+    }
+    Ok(node)
+}
+
+struct Parser<'a> {
+    text: &'a str,
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn error(&self, message: &str) -> String {
+        let end = self.pos.min(self.bytes.len());
+        let line = self.bytes[..end]
+            .iter()
+            .filter(|&&byte| byte == b'\n')
+            .count()
+            + 1;
+        format!("{} at line {}", message, line)
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self
+            .bytes
+            .get(self.pos)
+            .is_some_and(|byte| byte.is_ascii_whitespace())
+        {
+            self.pos += 1;
+        }
+    }
+
+    fn expect(&mut self, byte: u8) -> Result<(), String> {
+        self.skip_whitespace();
+        if self.bytes.get(self.pos) != Some(&byte) {
+            return Err(self.error(&format!("expected '{}'", byte as char)));
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    fn value(&mut self) -> Result<Node, String> {
+        self.skip_whitespace();
+        match self.bytes.get(self.pos) {
+            Some(b'{') => self.object(),
+            Some(b'[') => self.array(),
+            Some(b'"') => self.string().map(Node::Scalar),
+            Some(_) => self.scalar(),
+            None => Err(self.error("unexpected end of input")),
+        }
+    }
+
+    fn string(&mut self) -> Result<Range<usize>, String> {
+        let start = self.pos;
+        self.pos += 1;
+        loop {
+            match self.bytes.get(self.pos) {
+                Some(b'"') => {
+                    self.pos += 1;
+                    return Ok(start..self.pos);
+                }
+                Some(b'\\') => self.pos = (self.pos + 2).min(self.bytes.len()),
+                Some(_) => self.pos += 1,
+                None => return Err(self.error("unterminated string")),
+            }
+        }
+    }
+
+    fn scalar(&mut self) -> Result<Node, String> {
+        let start = self.pos;
+        while self
+            .bytes
+            .get(self.pos)
+            .is_some_and(|byte| !b",]} \t\r\n".contains(byte))
+        {
+            self.pos += 1;
+        }
+        serde_json::from_str::<serde_json::Value>(&self.text[start..self.pos])
+            .map_err(|_| self.error("invalid value"))?;
+        Ok(Node::Scalar(start..self.pos))
+    }
+
+    fn object(&mut self) -> Result<Node, String> {
+        let start = self.pos;
+        self.pos += 1;
+        let mut members = Vec::new();
+
+        self.skip_whitespace();
+        if self.bytes.get(self.pos) == Some(&b'}') {
+            self.pos += 1;
+            return Ok(Node::Object {
+                span: start..self.pos,
+                members,
+            });
+        }
+
+        loop {
+            self.skip_whitespace();
+            if self.bytes.get(self.pos) != Some(&b'"') {
+                return Err(self.error("expected a key"));
+            }
+            let key_span = self.string()?;
+            let key = serde_json::from_str(&self.text[key_span.clone()])
+                .map_err(|_| self.error("invalid key"))?;
+            self.expect(b':')?;
+            let value = self.value()?;
+            members.push(Member {
+                key,
+                start: key_span.start,
+                value,
+            });
+
+            self.skip_whitespace();
+            match self.bytes.get(self.pos) {
+                Some(b',') => self.pos += 1,
+                Some(b'}') => {
+                    self.pos += 1;
+                    return Ok(Node::Object {
+                        span: start..self.pos,
+                        members,
+                    });
+                }
+                _ => return Err(self.error("expected ',' or '}'")),
+            }
+        }
+    }
+
+    fn array(&mut self) -> Result<Node, String> {
+        let start = self.pos;
+        self.pos += 1;
+        let mut items = Vec::new();
+
+        self.skip_whitespace();
+        if self.bytes.get(self.pos) == Some(&b']') {
+            self.pos += 1;
+            return Ok(Node::Array {
+                span: start..self.pos,
+                items,
+            });
+        }
+
+        loop {
+            items.push(self.value()?);
+            self.skip_whitespace();
+            match self.bytes.get(self.pos) {
+                Some(b',') => self.pos += 1,
+                Some(b']') => {
+                    self.pos += 1;
+                    return Ok(Node::Array {
+                        span: start..self.pos,
+                        items,
+                    });
+                }
+                _ => return Err(self.error("expected ',' or ']'")),
+            }
+        }
+    }
+}
+
+/// The whitespace the line containing `pos` starts with.
+fn line_indent(text: &str, pos: usize) -> &str {
+    let line_start = text[..pos].rfind('\n').map_or(0, |index| index + 1);
+    let line = &text[line_start..];
+    &line[..line.len() - line.trim_start_matches([' ', '\t']).len()]
+}
+
+/// Indents every line of `value` after the first by `indent`.
+fn reindent(value: &str, indent: &str) -> String {
+    value.replace('\n', &format!("\n{}", indent))
+}
+
+fn splice(text: &str, range: Range<usize>, replacement: &str) -> String {
+    format!(
+        "{}{}{}",
+        &text[..range.start],
+        replacement,
+        &text[range.end..]
+    )
+}
+
+/// Replaces `node` with `value`, indented like the line `node` starts on.
+pub fn replace(text: &str, node: &Node, value: &str) -> String {
+    let span = node.span();
+    let value = reindent(value, line_indent(text, span.start));
+    splice(text, span, &value)
+}
+
+/// Adds an entry at the end of an object or array, laid out like the existing ones:
+/// on its own line when the container spans several lines, on the same line otherwise.
+fn append(text: &str, container: &Node, entry: &str) -> String {
+    let span = container.span();
+    let entries = container.entries();
+    let multiline = text[span.clone()].contains('\n');
+
+    match entries.last() {
+        Some(last) if multiline => {
+            let indent = line_indent(text, last.start);
+            let entry = format!(",\n{}{}", indent, reindent(entry, indent));
+            splice(text, last.end..last.end, &entry)
+        }
+        Some(last) => splice(text, last.end..last.end, &format!(", {}", entry)),
+        None => {
+            let outer = line_indent(text, span.start);
+            let indent = format!("{}  ", outer);
+            let inner = format!("\n{}{}\n{}", indent, reindent(entry, &indent), outer);
+            splice(text, span.start + 1..span.end - 1, &inner)
+        }
+    }
+}
+
+/// Adds `"key": value` at the end of `object`.
+pub fn insert_member(text: &str, object: &Node, key: &str, value: &str) -> String {
+    let key = serde_json::to_string(key).unwrap_or_default();
+    append(text, object, &format!("{}: {}", key, value))
+}
+
+/// Adds `value` at the end of `array`.
+pub fn push_item(text: &str, array: &Node, value: &str) -> String {
+    append(text, array, value)
+}
+
+/// Removes the member or item at `index` from `container` with its comma, leaving
+/// the others as they are.
+pub fn remove_entry(text: &str, container: &Node, index: usize) -> String {
+    let span = container.span();
+    let entries = container.entries();
+    let range = match (entries.len(), index) {
+        (1, 0) => span.start + 1..span.end - 1,
+        (count, index) if index + 1 < count => entries[index].start..entries[index + 1].start,
+        (count, index) if index + 1 == count => entries[index - 1].end..entries[index].end,
+        _ => return text.to_string(),
+    };
+    splice(text, range, "")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn insert(text: &str) -> String {
+        insert_member(text, &parse(text).unwrap(), "b", "2")
+    }
+
+    fn remove(text: &str, index: usize) -> String {
+        remove_entry(text, &parse(text).unwrap(), index)
+    }
+
+    #[test]
+    fn parse_locates_values() {
+        let text = r#"{ "a": [1, "x"], "b": {} }"#;
+        let root = parse(text).unwrap();
+        assert_eq!(root.span(), 0..text.len());
+
+        let a = &root.member("a").unwrap().value;
+        assert_eq!(&text[a.span()], r#"[1, "x"]"#);
+        match a {
+            Node::Array { items, .. } => assert_eq!(items[1].as_str(text).as_deref(), Some("x")),
+            _ => panic!("expected an array"),
+        }
+        assert_eq!(&text[root.member("b").unwrap().value.span()], "{}");
+    }
+
+    #[test]
+    fn parse_rejects_invalid_json() {
+        assert!(parse(r#"{ "a": 1, }"#).is_err());
+        assert!(parse(r#"{ "a": 1 } x"#).is_err());
+        assert!(parse(r#"{ "a": "#).is_err());
+    }
+
+    #[test]
+    fn parse_does_not_panic_on_trailing_backslash() {
+        assert!(parse("{\"a\": \"\\").is_err());
+        assert!(parse("\"\\").is_err());
+        assert!(parse("\"\\é").is_err());
+    }
+
+    #[test]
+    fn insert_member_on_one_line() {
+        assert_eq!(insert(r#"{ "a": 1 }"#), r#"{ "a": 1, "b": 2 }"#);
+    }
+
+    #[test]
+    fn insert_member_on_its_own_line() {
+        let text = "{\n    \"a\": 1\n}";
+        assert_eq!(insert(text), "{\n    \"a\": 1,\n    \"b\": 2\n}");
+    }
+
+    #[test]
+    fn insert_member_into_empty_object() {
+        assert_eq!(insert("{}"), "{\n  \"b\": 2\n}");
+        let nested = "{\n  \"rules\": {}\n}";
+        let root = parse(nested).unwrap();
+        let rules = &root.member("rules").unwrap().value;
+        assert_eq!(
+            insert_member(nested, rules, "b", "2"),
+            "{\n  \"rules\": {\n    \"b\": 2\n  }\n}"
+        );
+    }
+
+    #[test]
+    fn remove_first_entry() {
+        assert_eq!(
+            remove(r#"{ "a": 1, "b": 2, "c": 3 }"#, 0),
+            r#"{ "b": 2, "c": 3 }"#
+        );
+    }
+
+    #[test]
+    fn remove_middle_entry() {
+        assert_eq!(remove("[1, 2, 3]", 1), "[1, 3]");
+        let text = "{\n  \"a\": 1,\n  \"b\": 2,\n  \"c\": 3\n}";
+        assert_eq!(remove(text, 1), "{\n  \"a\": 1,\n  \"c\": 3\n}");
+    }
+
+    #[test]
+    fn remove_last_entry() {
+        assert_eq!(remove(r#"{ "a": 1, "b": 2 }"#, 1), r#"{ "a": 1 }"#);
+        let text = "{\n  \"a\": 1,\n  \"b\": 2\n}";
+        assert_eq!(remove(text, 1), "{\n  \"a\": 1\n}");
+    }
+
+    #[test]
+    fn remove_only_entry() {
+        assert_eq!(remove(r#"{ "a": 1 }"#, 0), "{}");
+        assert_eq!(remove("[\n  1\n]", 0), "[]");
+    }
+
+    #[test]
+    fn remove_missing_entry_leaves_text() {
+        assert_eq!(remove("[1]", 3), "[1]");
+        assert_eq!(remove("[]", 0), "[]");
+    }
+}
@@ -0,0 +1,142 @@
+use std::fmt;
+
+/// A BCP-47 language tag reduced to language, script and region, e.g. `zh-Hans-CN`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LanguageTag {
+    pub language: String,
+    pub script: Option<String>,
+    pub region: Option<String>,
+}
+
+impl LanguageTag {
+    /// Parses `ru`, `en-GB`, `zh-Hans` or `pt_BR`. Variants and extensions are not
+    /// supported. The subtags are normalized to `ll-Ssss-RR` case.
+    pub fn parse(tag: &str) -> Option<LanguageTag> {
+        let mut subtags = tag.split(['-', '_']);
+
+        let language = subtags.next()?;
+        if !(2..=3).contains(&language.len()) || !language.chars().all(|c| c.is_ascii_alphabetic())
+        {
+            return None;
+        }
+
+        let mut parsed = LanguageTag {
+            language: language.to_ascii_lowercase(),
+            script: None,
+            region: None,
+        };
+
+        let mut next = subtags.next();
+        if let Some(script) =
+            next.filter(|s| s.len() == 4 && s.chars().all(|c| c.is_ascii_alphabetic()))
+        {
+            let mut chars = script.chars();
+            let first = chars.next()?.to_ascii_uppercase();
+            parsed.script = Some(format!("{}{}", first, chars.as_str().to_ascii_lowercase()));
+            next = subtags.next();
+        }
+
+        if let Some(region) = next {
+            let is_region = (region.len() == 2 && region.chars().all(|c| c.is_ascii_alphabetic()))
+                || (region.len() == 3 && region.chars().all(|c| c.is_ascii_digit()));
+            if !is_region {
+                return None;
+            }
+            parsed.region = Some(region.to_ascii_uppercase());
+            next = subtags.next();
+        }
+
+        if next.is_some() {
+            return None;
+        }
+        Some(parsed)
+    }
+
+    /// The tag followed by ever less specific ones, down to the bare language:
+    /// `zh-Hans-CN`, `zh-Hans`, `zh`.
+    pub fn fallbacks(&self) -> Vec<String> {
+        let mut fallbacks = vec![self.to_string()];
+        if self.region.is_some() {
+            if let Some(ref script) = self.script {
+                fallbacks.push(format!("{}-{}", self.language, script));
+            }
+        }
+        if self.script.is_some() || self.region.is_some() {
+            fallbacks.push(self.language.clone());
+        }
+        fallbacks
+    }
+}
+
+impl fmt::Display for LanguageTag {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.language)?;
+        if let Some(ref script) = self.script {
+            write!(f, "-{}", script)?;
+        }
+        if let Some(ref region) = self.region {
+            write!(f, "-{}", region)?;
+        }
+        Ok(())
+    }
+}
+
+/// Whether `tag` falls under `range`: it is the same tag or a more specific one,
+/// compared without regard to case. `zh` covers `zh-Hans`; `en-GB` does not cover `en`.
+pub fn tag_matches(range: &str, tag: &str) -> bool {
+    tag.len() >= range.len()
+        && tag.as_bytes()[..range.len()].eq_ignore_ascii_case(range.as_bytes())
+        && matches!(
+            tag.as_bytes().get(range.len()),
+            None | Some(b'-') | Some(b'_')
+        )
+}
+
+/// The language subtag of `tag`, lowercased.
+pub fn primary_language(tag: &str) -> String {
+    tag.split(['-', '_'])
+        .next()
+        .unwrap_or_default()
+        .to_ascii_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tags_are_parsed_and_normalized() {
+        let tag = LanguageTag::parse("ZH_hans_cn").unwrap();
+        assert_eq!(tag.language, "zh");
+        assert_eq!(tag.script.as_deref(), Some("Hans"));
+        assert_eq!(tag.region.as_deref(), Some("CN"));
+        assert_eq!(tag.to_string(), "zh-Hans-CN");
+        assert_eq!(LanguageTag::parse("es-419").unwrap().to_string(), "es-419");
+    }
+
+    #[test]
+    fn invalid_tags_are_rejected() {
+        assert_eq!(LanguageTag::parse(""), None);
+        assert_eq!(LanguageTag::parse("english"), None);
+        assert_eq!(LanguageTag::parse("en-G"), None);
+        assert_eq!(LanguageTag::parse("de-CH-1996"), None);
+    }
+
+    #[test]
+    fn fallbacks_go_down_to_the_language() {
+        let fallbacks = |tag: &str| LanguageTag::parse(tag).unwrap().fallbacks();
+        assert_eq!(fallbacks("en-GB"), ["en-GB", "en"]);
+        assert_eq!(fallbacks("zh-Hans-CN"), ["zh-Hans-CN", "zh-Hans", "zh"]);
+        assert_eq!(fallbacks("ru"), ["ru"]);
+    }
+
+    #[test]
+    fn ranges_cover_more_specific_tags() {
+        assert!(tag_matches("en", "en-GB"));
+        assert!(tag_matches("EN", "en_gb"));
+        assert!(tag_matches("zh-Hans", "zh-Hans-CN"));
+        assert!(!tag_matches("en-GB", "en"));
+        assert!(!tag_matches("en", "eng"));
+        assert_eq!(primary_language("PT_br"), "pt");
+    }
+}
@@ -0,0 +1,256 @@
+use serde_json::{Map, Value};
+
+/// The layout table shipped with the program.
+const BUILTIN_LAYOUTS: &str = include_str!("layouts.json");
+
+/// How a layout code from the config is recognized on each platform.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LayoutDefinition {
+    /// Canonical code, e.g. `DE`.
+    pub code: String,
+    /// Other codes and names accepted for it in the config, e.g. `GERMAN`.
+    pub aliases: Vec<String>,
+    /// BCP-47 language tag, e.g. `de` or `zh-Hans`.
+    pub language: Option<String>,
+    /// macOS input source ids, or prefixes of them, most preferred first.
+    pub ids: Vec<String>,
+    /// Input source names in the languages macOS may be set to.
+    pub names: Vec<String>,
+    /// XKB layout names.
+    pub xkb: Vec<String>,
+    /// IBus engine names.
+    pub ibus: Vec<String>,
+    /// Short names for the input modes of an input method, e.g. `hiragana`, with the
+    /// macOS input mode id each stands for.
+    pub modes: Vec<(String, String)>,
+}
+
+impl LayoutDefinition {
+    /// Whether `name` is the code or one of the aliases, in any case.
+    pub fn is_named(&self, name: &str) -> bool {
+        self.code.eq_ignore_ascii_case(name)
+            || self
+                .aliases
+                .iter()
+                .any(|alias| alias.eq_ignore_ascii_case(name))
+    }
+
+    /// The input mode id for `mode`, which is one of our short names in any case or
+    /// else taken to be an input mode id already.
+    pub fn input_mode<'a>(&'a self, mode: &'a str) -> &'a str {
+        self.modes
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(mode))
+            .map_or(mode, |(_, mode_id)| mode_id.as_str())
+    }
+
+    /// Patterns an input source id or name is matched against, ids first.
+    pub fn search_patterns(&self) -> Vec<String> {
+        self.ids.iter().chain(&self.names).cloned().collect()
+    }
+
+    /// Adds the entries of `other` in front of ours, so that they are preferred.
+    fn extend(&mut self, other: LayoutDefinition) {
+        fn prepend(list: &mut Vec<String>, mut front: Vec<String>) {
+            front.retain(|item| !list.contains(item));
+            front.append(list);
+            *list = front;
+        }
+
+        prepend(&mut self.aliases, other.aliases);
+        prepend(&mut self.ids, other.ids);
+        prepend(&mut self.names, other.names);
+        prepend(&mut self.xkb, other.xkb);
+        prepend(&mut self.ibus, other.ibus);
+        self.modes
+            .retain(|(name, _)| !other.modes.iter().any(|(known, _)| known == name));
+        let mut modes = other.modes;
+        modes.append(&mut self.modes);
+        self.modes = modes;
+        if other.language.is_some() {
+            self.language = other.language;
+        }
+    }
+}
+
+/// Known layouts: the built-in table, extended by the `layouts` section of the config.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LayoutTable {
+    layouts: Vec<LayoutDefinition>,
+}
+
+impl LayoutTable {
+    pub fn builtin() -> LayoutTable {
+        let value: Value =
+            serde_json::from_str(BUILTIN_LAYOUTS).expect("built-in layout table is valid JSON");
+        let layouts = value
+            .as_object()
+            .and_then(|object| parse_layouts(object).ok())
+            .expect("built-in layout table is well-formed");
+        LayoutTable { layouts }
+    }
+
+    /// Adds user-defined layouts; a definition for a code that is already known
+    /// extends it instead of replacing it.
+    pub fn extend(&mut self, layouts: Vec<LayoutDefinition>) {
+        for layout in layouts {
+            match self
+                .layouts
+                .iter_mut()
+                .find(|known| known.is_named(&layout.code))
+            {
+                Some(known) => known.extend(layout),
+                None => self.layouts.push(layout),
+            }
+        }
+    }
+
+    /// Finds the layout with `name` as its code or an alias.
+    pub fn lookup(&self, name: &str) -> Option<&LayoutDefinition> {
+        self.layouts.iter().find(|layout| layout.is_named(name))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &LayoutDefinition> {
+        self.layouts.iter()
+    }
+}
+
+/// Splits a layout target into the layout and the input mode after a colon, e.g.
+/// `JA:hiragana`. Targets without a colon have no mode.
+pub fn split_input_mode(target: &str) -> (&str, Option<&str>) {
+    match target.split_once(':') {
+        Some((layout, mode)) => (layout, Some(mode)),
+        None => (target, None),
+    }
+}
+
+fn string_list(code: &str, field: &str, value: Option<&Value>) -> Result<Vec<String>, String> {
+    let error = || format!("{} of layout '{}' must be a list of strings", field, code);
+    match value {
+        None => Ok(Vec::new()),
+        Some(Value::String(item)) => Ok(vec![item.clone()]),
+        Some(Value::Array(items)) => items
+            .iter()
+            .map(|item| item.as_str().map(str::to_string).ok_or_else(error))
+            .collect(),
+        Some(_) => Err(error()),
+    }
+}
+
+fn mode_map(code: &str, value: Option<&Value>) -> Result<Vec<(String, String)>, String> {
+    let error = || {
+        format!(
+            "modes of layout '{}' must map names to input mode ids",
+            code
+        )
+    };
+    match value {
+        None | Some(Value::Null) => Ok(Vec::new()),
+        Some(Value::Object(modes)) => modes
+            .iter()
+            .map(|(name, mode_id)| {
+                mode_id
+                    .as_str()
+                    .map(|mode_id| (name.clone(), mode_id.to_string()))
+                    .ok_or_else(error)
+            })
+            .collect(),
+        Some(_) => Err(error()),
+    }
+}
+
+/// Parses a `"CODE": { "aliases": [...], "language": "..", "ids": [...], "names": [...],
+/// "xkb": [...], "ibus": [...], "modes": {...} }` map. Every field is optional.
+pub fn parse_layouts(object: &Map<String, Value>) -> Result<Vec<LayoutDefinition>, String> {
+    object
+        .iter()
+        .map(|(code, value)| {
+            let fields = value
+                .as_object()
+                .ok_or_else(|| format!("layout '{}' must be an object", code))?;
+
+            let language = match fields.get("language") {
+                None | Some(Value::Null) => None,
+                Some(Value::String(language)) => Some(language.clone()),
+                Some(_) => return Err(format!("language of layout '{}' must be a string", code)),
+            };
+
+            Ok(LayoutDefinition {
+                code: code.clone(),
+                aliases: string_list(code, "aliases", fields.get("aliases"))?,
+                language,
+                ids: string_list(code, "ids", fields.get("ids"))?,
+                names: string_list(code, "names", fields.get("names"))?,
+                xkb: string_list(code, "xkb", fields.get("xkb"))?,
+                ibus: string_list(code, "ibus", fields.get("ibus"))?,
+                modes: mode_map(code, fields.get("modes"))?,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(json: &str) -> Result<Vec<LayoutDefinition>, String> {
+        let value: Value = serde_json::from_str(json).unwrap();
+        parse_layouts(value.as_object().unwrap())
+    }
+
+    #[test]
+    fn builtin_table_looks_up_codes_and_aliases() {
+        let table = LayoutTable::builtin();
+        let english = table.lookup("en").unwrap();
+        assert_eq!(english.code, "EN");
+        assert_eq!(english.language.as_deref(), Some("en"));
+        assert_eq!(table.lookup("English"), Some(english));
+        assert_eq!(table.lookup("ua").unwrap().code, "UK");
+        assert!(table.lookup("Klingon").is_none());
+    }
+
+    #[test]
+    fn user_layouts_extend_known_codes() {
+        let mut table = LayoutTable::builtin();
+        table.extend(
+            parse(
+                r#"{ "english": { "ids": ["com.example.keylayout.Mine", "com.apple.keylayout.US"], "language": "en-GB" } }"#,
+            )
+            .unwrap(),
+        );
+
+        let english = table.lookup("EN").unwrap();
+        assert_eq!(english.language.as_deref(), Some("en-GB"));
+        assert_eq!(english.ids[0], "com.example.keylayout.Mine");
+        assert_eq!(
+            english
+                .ids
+                .iter()
+                .filter(|id| *id == "com.apple.keylayout.US")
+                .count(),
+            1
+        );
+        assert!(english.search_patterns().contains(&"U.S.".to_string()));
+    }
+
+    #[test]
+    fn user_layouts_add_new_codes() {
+        let mut table = LayoutTable::builtin();
+        let count = table.iter().count();
+        table
+            .extend(parse(r#"{ "TLH": { "aliases": "KLINGON", "names": ["Klingon"] } }"#).unwrap());
+
+        assert_eq!(table.iter().count(), count + 1);
+        let klingon = table.lookup("klingon").unwrap();
+        assert_eq!(klingon.code, "TLH");
+        assert_eq!(klingon.search_patterns(), ["Klingon"]);
+        assert_eq!(klingon.language, None);
+    }
+
+    #[test]
+    fn malformed_layouts_are_rejected() {
+        assert!(parse(r#"{ "XX": "xx" }"#).is_err());
+        assert!(parse(r#"{ "XX": { "ids": [1] } }"#).is_err());
+        assert!(parse(r#"{ "XX": { "language": ["xx"] } }"#).is_err());
+    }
+}
@@ -1,10 +1,97 @@
 use cocoa::base::id;
-use std::os::raw::c_char;
+use cocoa::foundation::NSPoint;
+use std::os::raw::{c_char, c_void};
 
 pub const K_TIS_PROPERTY_INPUT_SOURCE_ID: &str = "TISPropertyInputSourceID";
 pub const K_TIS_PROPERTY_LOCALIZED_NAME: &str = "TISPropertyLocalizedName";
+pub const K_TIS_PROPERTY_INPUT_SOURCE_LANGUAGES: &str = "TISPropertyInputSourceLanguages";
+pub const K_TIS_PROPERTY_INPUT_SOURCE_IS_ENABLED: &str = "TISPropertyInputSourceIsEnabled";
+pub const K_TIS_PROPERTY_INPUT_SOURCE_TYPE: &str = "TISPropertyInputSourceType";
+pub const K_TIS_PROPERTY_INPUT_SOURCE_IS_SELECT_CAPABLE: &str =
+    "TISPropertyInputSourceIsSelectCapable";
+pub const K_TIS_PROPERTY_INPUT_MODE_ID: &str = "TISPropertyInputModeID";
+pub const K_TIS_PROPERTY_BUNDLE_ID: &str = "TISPropertyBundleID";
+pub const K_TIS_PROPERTY_INPUT_SOURCE_IS_ENABLE_CAPABLE: &str =
+    "TISPropertyInputSourceIsEnableCapable";
+pub const K_TIS_NOTIFY_ENABLED_KEYBOARD_INPUT_SOURCES_CHANGED: &str =
+    "com.apple.Carbon.TISNotifyEnabledKeyboardInputSourcesChanged";
 pub const K_UTF8_ENCODING: u32 = 0x08000100;
 
+pub const K_CG_WINDOW_LIST_OPTION_ALL: u32 = 0;
+pub const K_CG_WINDOW_LIST_OPTION_ON_SCREEN_ONLY: u32 = 1 << 0;
+pub const K_CG_WINDOW_LIST_EXCLUDE_DESKTOP_ELEMENTS: u32 = 1 << 4;
+pub const K_CG_NULL_WINDOW_ID: u32 = 0;
+pub const K_CG_WINDOW_NUMBER: &str = "kCGWindowNumber";
+pub const K_CG_WINDOW_OWNER_PID: &str = "kCGWindowOwnerPID";
+pub const K_CG_WINDOW_OWNER_NAME: &str = "kCGWindowOwnerName";
+pub const K_CG_WINDOW_LAYER: &str = "kCGWindowLayer";
+pub const K_CG_WINDOW_BOUNDS: &str = "kCGWindowBounds";
+
+pub const PROC_PIDTBSDINFO: i32 = 3;
+pub const PROC_PIDVNODEPATHINFO: i32 = 9;
+pub const MAXPATHLEN: usize = 1024;
+pub const CTL_KERN: i32 = 1;
+pub const KERN_PROCARGS2: i32 = 49;
+pub const NODEV: u32 = u32::MAX;
+
+pub const K_AX_ERROR_SUCCESS: i32 = 0;
+
+/// `struct proc_bsdinfo` from `<sys/proc_info.h>`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct ProcBsdInfo {
+    pub pbi_flags: u32,
+    pub pbi_status: u32,
+    pub pbi_xstatus: u32,
+    pub pbi_pid: u32,
+    pub pbi_ppid: u32,
+    pub pbi_uid: u32,
+    pub pbi_gid: u32,
+    pub pbi_ruid: u32,
+    pub pbi_rgid: u32,
+    pub pbi_svuid: u32,
+    pub pbi_svgid: u32,
+    pub rfu_1: u32,
+    pub pbi_comm: [c_char; 16],
+    pub pbi_name: [c_char; 32],
+    pub pbi_nfiles: u32,
+    pub pbi_pgid: u32,
+    pub pbi_pjobc: u32,
+    pub e_tdev: u32,
+    pub e_tpgid: u32,
+    pub pbi_nice: i32,
+    pub pbi_start_tvsec: u64,
+    pub pbi_start_tvusec: u64,
+}
+
+/// `struct tm` from `<time.h>`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct Tm {
+    pub tm_sec: i32,
+    pub tm_min: i32,
+    pub tm_hour: i32,
+    pub tm_mday: i32,
+    pub tm_mon: i32,
+    pub tm_year: i32,
+    pub tm_wday: i32,
+    pub tm_yday: i32,
+    pub tm_isdst: i32,
+    pub tm_gmtoff: i64,
+    pub tm_zone: *const c_char,
+}
+
+/// `struct proc_vnodepathinfo` from `<sys/proc_info.h>`; the `vnode_info` parts are
+/// kept opaque since only the paths are read.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct ProcVnodePathInfo {
+    pub pvi_cdir_info: [u8; 152],
+    pub pvi_cdir_path: [c_char; MAXPATHLEN],
+    pub pvi_rdir_info: [u8; 152],
+    pub pvi_rdir_path: [c_char; MAXPATHLEN],
+}
+
 #[allow(non_snake_case)]
 extern "C" {
     pub fn CFRunLoopRun();
@@ -12,6 +99,7 @@ extern "C" {
     pub fn TISGetInputSourceProperty(input_source: id, property_key: id) -> id;
     pub fn TISCopyInputSourceForLanguage(language: id) -> id;
     pub fn TISSelectInputSource(input_source: id) -> i32;
+    pub fn TISEnableInputSource(input_source: id) -> i32;
     pub fn TISCreateInputSourceList(properties: id, include_all_installed: bool) -> id;
     pub fn CFStringGetCString(
         the_string: id,
@@ -23,9 +111,42 @@ extern "C" {
     pub fn CFGetTypeID(cf: id) -> usize;
     pub fn CFStringGetTypeID() -> usize;
     pub fn CFRelease(cf: id);
+    pub fn CFRetain(cf: id) -> id;
     pub fn CFArrayGetCount(the_array: id) -> isize;
     pub fn CFArrayGetValueAtIndex(the_array: id, idx: isize) -> id;
     pub fn CFStringCreateWithCString(alloc: id, c_str: *const c_char, encoding: u32) -> id;
+    pub fn CGWindowListCopyWindowInfo(option: u32, relative_to_window: u32) -> id;
+    pub fn CGGetDisplaysWithPoint(
+        point: NSPoint,
+        max_displays: u32,
+        displays: *mut u32,
+        matching_display_count: *mut u32,
+    ) -> i32;
+    pub fn CGSMainConnectionID() -> i32;
+    pub fn CGSGetActiveSpace(connection: i32) -> u64;
+    pub fn CGSCopyManagedDisplaySpaces(connection: i32) -> id;
+    pub fn AXIsProcessTrusted() -> bool;
+    pub fn AXUIElementCreateApplication(pid: i32) -> id;
+    pub fn AXUIElementCopyAttributeValue(element: id, attribute: id, value: *mut id) -> i32;
+    pub fn proc_listchildpids(ppid: i32, buffer: *mut c_void, buffersize: i32) -> i32;
+    pub fn proc_pidinfo(
+        pid: i32,
+        flavor: i32,
+        arg: u64,
+        buffer: *mut c_void,
+        buffersize: i32,
+    ) -> i32;
+    pub fn time(tloc: *mut i64) -> i64;
+    pub fn umask(mask: u16) -> u16;
+    pub fn localtime_r(clock: *const i64, result: *mut Tm) -> *mut Tm;
+    pub fn sysctl(
+        name: *mut i32,
+        namelen: u32,
+        oldp: *mut c_void,
+        oldlenp: *mut usize,
+        newp: *mut c_void,
+        newlen: usize,
+    ) -> i32;
 }
 
 pub fn run_main_loop() {
@@ -0,0 +1,138 @@
+use crate::core::config::MemoryMode;
+
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+/// Input source ids last used per window and per application.
+#[derive(Debug, Default)]
+pub struct LayoutMemory {
+    windows: HashMap<u32, String>,
+    apps: HashMap<String, String>,
+}
+
+impl LayoutMemory {
+    pub fn new() -> LayoutMemory {
+        LayoutMemory::default()
+    }
+
+    pub fn remember(&mut self, app: &str, window: Option<u32>, source_id: &str) {
+        if let Some(window) = window {
+            self.windows.insert(window, source_id.to_string());
+        }
+        self.apps.insert(app.to_string(), source_id.to_string());
+    }
+
+    /// Returns the remembered input source id, preferring the window over the application.
+    pub fn recall(&self, mode: MemoryMode, app: &str, window: Option<u32>) -> Option<&str> {
+        let by_window = match (mode, window) {
+            (MemoryMode::Window, Some(window)) => self.windows.get(&window),
+            _ => None,
+        };
+
+        match mode {
+            MemoryMode::Off => None,
+            MemoryMode::App | MemoryMode::Window => by_window
+                .or_else(|| self.apps.get(app))
+                .map(|source_id| source_id.as_str()),
+        }
+    }
+
+    /// Drops entries for windows that no longer exist.
+    pub fn retain_windows(&mut self, alive: &HashSet<u32>) {
+        self.windows.retain(|window, _| alive.contains(window));
+    }
+}
+
+/// A layout the user selected by hand while an application window was focused.
+#[derive(Debug, Clone)]
+pub struct ManualOverride {
+    pub app: String,
+    pub window: Option<u32>,
+    pub since: Instant,
+}
+
+impl ManualOverride {
+    /// Whether automatic switching is still paused for this application and window.
+    pub fn is_active(
+        &self,
+        app: &str,
+        window: Option<u32>,
+        grace_period: Option<Duration>,
+    ) -> bool {
+        self.app == app
+            && self.window == window
+            && grace_period.is_none_or(|grace_period| self.since.elapsed() < grace_period)
+    }
+}
+
+/// What to go back to when an overlay application (a launcher, a password manager
+/// popup) loses focus.
+#[derive(Debug, Clone)]
+pub struct OverlayReturn {
+    /// Application that was focused when the overlay appeared.
+    pub app: String,
+    /// Input source that was active in it.
+    pub source_id: String,
+    pub manual_override: Option<ManualOverride>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn window_memory_falls_back_to_the_application() {
+        let mut memory = LayoutMemory::new();
+        memory.remember("Safari", Some(1), "com.apple.keylayout.Russian");
+        memory.remember("Safari", Some(2), "com.apple.keylayout.US");
+
+        let recall = |mode, window| memory.recall(mode, "Safari", window);
+        assert_eq!(
+            recall(MemoryMode::Window, Some(1)),
+            Some("com.apple.keylayout.Russian")
+        );
+        assert_eq!(
+            recall(MemoryMode::Window, Some(3)),
+            Some("com.apple.keylayout.US")
+        );
+        assert_eq!(
+            recall(MemoryMode::App, Some(1)),
+            Some("com.apple.keylayout.US")
+        );
+        assert_eq!(recall(MemoryMode::Off, Some(1)), None);
+        assert_eq!(memory.recall(MemoryMode::App, "Mail", None), None);
+    }
+
+    #[test]
+    fn closed_windows_are_forgotten() {
+        let mut memory = LayoutMemory::new();
+        memory.remember("Safari", Some(1), "com.apple.keylayout.Russian");
+        memory.remember("Safari", Some(2), "com.apple.keylayout.US");
+        memory.remember("Safari", None, "com.apple.keylayout.German");
+        memory.retain_windows(&HashSet::from([2]));
+
+        assert_eq!(
+            memory.recall(MemoryMode::Window, "Safari", Some(1)),
+            Some("com.apple.keylayout.German")
+        );
+        assert_eq!(
+            memory.recall(MemoryMode::Window, "Safari", Some(2)),
+            Some("com.apple.keylayout.US")
+        );
+    }
+
+    #[test]
+    fn manual_override_holds_for_its_window_until_the_grace_period_ends() {
+        let manual = ManualOverride {
+            app: "Safari".to_string(),
+            window: Some(1),
+            since: Instant::now() - Duration::from_secs(10),
+        };
+
+        assert!(manual.is_active("Safari", Some(1), None));
+        assert!(manual.is_active("Safari", Some(1), Some(Duration::from_secs(60))));
+        assert!(!manual.is_active("Safari", Some(1), Some(Duration::from_secs(5))));
+        assert!(!manual.is_active("Safari", Some(2), None));
+        assert!(!manual.is_active("Mail", Some(1), None));
+    }
+}
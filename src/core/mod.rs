@@ -1,5 +1,23 @@
+pub mod accessibility;
+pub mod cli;
+pub mod client;
 pub mod config;
+pub mod control;
+pub mod enforce;
+pub mod hooks;
+pub mod inventory;
+pub mod json_edit;
+pub mod language;
+pub mod layouts;
 pub mod macos_api;
+pub mod memory;
 pub mod monitor;
 pub mod observer;
+pub mod outcome;
+pub mod process;
+pub mod project;
+pub mod resolve;
+pub mod rules;
+pub mod schedule;
+pub mod sources;
 pub mod switcher;
@@ -1,13 +1,20 @@
 use crate::core::macos_api::{
-    CFGetTypeID, CFRelease, CFStringGetCString, CFStringGetLength, CFStringGetTypeID,
-    TISCopyCurrentKeyboardInputSource, TISGetInputSourceProperty, K_TIS_PROPERTY_INPUT_SOURCE_ID,
+    CFArrayGetCount, CFArrayGetValueAtIndex, CFGetTypeID, CFRelease, CFStringGetCString,
+    CFStringGetLength, CFStringGetTypeID, CGGetDisplaysWithPoint, CGSCopyManagedDisplaySpaces,
+    CGSGetActiveSpace, CGSMainConnectionID, CGWindowListCopyWindowInfo,
+    TISCopyCurrentKeyboardInputSource, TISGetInputSourceProperty, K_CG_NULL_WINDOW_ID,
+    K_CG_WINDOW_BOUNDS, K_CG_WINDOW_LAYER, K_CG_WINDOW_LIST_EXCLUDE_DESKTOP_ELEMENTS,
+    K_CG_WINDOW_LIST_OPTION_ALL, K_CG_WINDOW_LIST_OPTION_ON_SCREEN_ONLY, K_CG_WINDOW_NUMBER,
+    K_CG_WINDOW_OWNER_NAME, K_CG_WINDOW_OWNER_PID, K_TIS_PROPERTY_INPUT_SOURCE_ID,
     K_TIS_PROPERTY_LOCALIZED_NAME, K_UTF8_ENCODING,
 };
+use crate::core::{accessibility, process, rules, switcher};
 use crate::state;
 
 use cocoa::base::{id, nil};
-use cocoa::foundation::NSString;
+use cocoa::foundation::{NSPoint, NSString};
 use objc::{class, msg_send, sel, sel_impl};
+use std::collections::HashSet;
 use std::ffi::CStr;
 use std::os::raw::c_char;
 
@@ -17,7 +24,9 @@ use std::os::raw::c_char;
 ///
 /// This function is unsafe because:
 /// 1. It calls FFI functions (Objective-C runtime via `msg_send!`) to interact with macOS APIs.
-/// 2. It writes to the `static mut` variable `state::CURRENT_APP`.
+/// 2. It writes to the `static mut` variables `state::CURRENT_APP`, `state::CURRENT_PID`,
+///    `state::CURRENT_WINDOW`, `state::CURRENT_WINDOW_ROLE`, `state::CURRENT_WORKSPACE`,
+///    `state::CURRENT_OUTPUT`, `state::CURRENT_PROCESS` and `state::CURRENT_DIRECTORY`.
 ///
 ///     The caller must ensure that access to `state::CURRENT_APP` is synchronized if
 ///     the application is or becomes multi-threaded.
@@ -34,7 +43,325 @@ pub unsafe fn update_active_window() {
                 state::CURRENT_APP = Some(rust_string);
             }
         }
+
+        let pid: i32 = msg_send![active_app, processIdentifier];
+        let mut window = frontmost_window(pid);
+        state::CURRENT_PID = Some(pid);
+
+        // An overlay panel has focus while the application below stays frontmost.
+        if let Some((owner, panel_window)) = overlay_panel() {
+            state::CURRENT_APP = Some(owner);
+            window = Some(panel_window);
+        }
+
+        state::CURRENT_WINDOW = window.map(|window| window.number);
+        state::CURRENT_OUTPUT = if needs_desktop() {
+            window.and_then(|window| display_name_at(window.center))
+        } else {
+            None
+        };
+        state::CURRENT_WINDOW_ROLE = if needs_window_role() {
+            accessibility::focused_window_role(pid)
+        } else {
+            None
+        };
+    }
+
+    state::CURRENT_WORKSPACE = if needs_desktop() {
+        active_space_number().map(|number| number.to_string())
+    } else {
+        None
+    };
+
+    update_foreground_process();
+}
+
+/// Whether a rule for the current application depends on the workspace or output,
+/// which are only looked up then since the workspace needs private CoreGraphics calls.
+unsafe fn needs_desktop() -> bool {
+    match (
+        &*std::ptr::addr_of!(state::CURRENT_APP),
+        &*std::ptr::addr_of!(state::CONFIG),
+    ) {
+        (Some(app_name), Some(config)) => rules::needs_desktop(&config.rules, app_name),
+        _ => false,
+    }
+}
+
+/// Whether a rule for the current application depends on the focused window's role,
+/// which is only queried then since it goes through the accessibility API.
+unsafe fn needs_window_role() -> bool {
+    match (
+        &*std::ptr::addr_of!(state::CURRENT_APP),
+        &*std::ptr::addr_of!(state::CONFIG),
+    ) {
+        (Some(app_name), Some(config)) => rules::needs_window_role(&config.rules, app_name),
+        _ => false,
+    }
+}
+
+/// Updates the global state with the foreground process of the focused terminal and
+/// the working directory used to look up project files: the foreground process's, or
+/// the application's own when it has none. The process tree is only inspected for
+/// applications that have rules or project files depending on it.
+///
+/// # Safety
+///
+/// This function is unsafe because it calls libproc FFI functions and reads and writes
+/// `static mut` variables in `state`.
+pub unsafe fn update_foreground_process() {
+    let (needs_process, needs_directory) = match (
+        &*std::ptr::addr_of!(state::CURRENT_APP),
+        &*std::ptr::addr_of!(state::CONFIG),
+    ) {
+        (Some(app_name), Some(config)) => (
+            rules::needs_process(&config.rules, app_name),
+            config.project_files.enabled_for(app_name),
+        ),
+        _ => (false, false),
+    };
+
+    state::CURRENT_PROCESS = match (needs_process || needs_directory, state::CURRENT_PID) {
+        (true, Some(pid)) => process::foreground_process(pid),
+        _ => None,
+    };
+
+    state::CURRENT_DIRECTORY = match (needs_directory, state::CURRENT_PID) {
+        (true, Some(pid)) => (*std::ptr::addr_of!(state::CURRENT_PROCESS))
+            .as_ref()
+            .and_then(|process| process.working_directory.clone())
+            .or_else(|| process::working_directory(pid)),
+        _ => None,
+    };
+}
+
+unsafe fn window_info_number(info: id, key: &str) -> Option<i64> {
+    let key = NSString::alloc(nil).init_str(key);
+    let value: id = msg_send![info, objectForKey: key];
+    if value == nil {
+        return None;
+    }
+    let number: i64 = msg_send![value, longLongValue];
+    Some(number)
+}
+
+unsafe fn window_info_double(info: id, key: &str) -> Option<f64> {
+    let key = NSString::alloc(nil).init_str(key);
+    let value: id = msg_send![info, objectForKey: key];
+    if value == nil {
+        return None;
+    }
+    let number: f64 = msg_send![value, doubleValue];
+    Some(number)
+}
+
+#[derive(Clone, Copy)]
+struct FrontWindow {
+    number: u32,
+    /// Center of the window in global display coordinates (origin at the top left).
+    center: NSPoint,
+}
+
+unsafe fn window_center(info: id) -> Option<NSPoint> {
+    let key = NSString::alloc(nil).init_str(K_CG_WINDOW_BOUNDS);
+    let bounds: id = msg_send![info, objectForKey: key];
+    if bounds == nil {
+        return None;
+    }
+
+    Some(NSPoint::new(
+        window_info_double(bounds, "X")? + window_info_double(bounds, "Width")? / 2.0,
+        window_info_double(bounds, "Y")? + window_info_double(bounds, "Height")? / 2.0,
+    ))
+}
+
+unsafe fn window_info_string(info: id, key: &str) -> Option<String> {
+    let key = NSString::alloc(nil).init_str(key);
+    let value: id = msg_send![info, objectForKey: key];
+    if value == nil {
+        return None;
+    }
+    let c_string: *const c_char = msg_send![value, UTF8String];
+    if c_string.is_null() {
+        return None;
+    }
+    Some(CStr::from_ptr(c_string).to_string_lossy().to_string())
+}
+
+/// Layer of menu bar items, which overlay applications such as Alfred keep on screen.
+const STATUS_ITEM_LAYER: i64 = 25;
+
+/// Returns the overlay application with a panel on screen above the normal windows,
+/// e.g. Spotlight's search field, and that panel. Such panels take keyboard focus
+/// without activating their application, so no activation notification announces them
+/// and the frontmost application stays the one below.
+unsafe fn overlay_panel() -> Option<(String, FrontWindow)> {
+    let config = (*std::ptr::addr_of!(state::CONFIG)).as_ref()?;
+    if config.overlays.is_empty() {
+        return None;
+    }
+    let frontmost = (*std::ptr::addr_of!(state::CURRENT_APP)).as_deref();
+    if frontmost.is_some_and(|app| switcher::is_overlay(config, app)) {
+        return None;
+    }
+
+    let windows = CGWindowListCopyWindowInfo(
+        K_CG_WINDOW_LIST_OPTION_ON_SCREEN_ONLY | K_CG_WINDOW_LIST_EXCLUDE_DESKTOP_ELEMENTS,
+        K_CG_NULL_WINDOW_ID,
+    );
+    if windows == nil {
+        return None;
+    }
+
+    let mut found = None;
+    for i in 0..CFArrayGetCount(windows) {
+        let info = CFArrayGetValueAtIndex(windows, i);
+        let layer = window_info_number(info, K_CG_WINDOW_LAYER);
+        if !layer.is_some_and(|layer| layer > 0 && layer != STATUS_ITEM_LAYER) {
+            continue;
+        }
+        let owner = match window_info_string(info, K_CG_WINDOW_OWNER_NAME) {
+            Some(owner) if !owner.is_empty() && switcher::is_overlay(config, &owner) => owner,
+            _ => continue,
+        };
+        if let Some(number) = window_info_number(info, K_CG_WINDOW_NUMBER) {
+            let window = FrontWindow {
+                number: number as u32,
+                center: window_center(info).unwrap_or(NSPoint::new(0.0, 0.0)),
+            };
+            found = Some((owner, window));
+            break;
+        }
+    }
+
+    CFRelease(windows);
+    found
+}
+
+/// Returns the frontmost normal-level window owned by `pid`.
+unsafe fn frontmost_window(pid: i32) -> Option<FrontWindow> {
+    let windows = CGWindowListCopyWindowInfo(
+        K_CG_WINDOW_LIST_OPTION_ON_SCREEN_ONLY | K_CG_WINDOW_LIST_EXCLUDE_DESKTOP_ELEMENTS,
+        K_CG_NULL_WINDOW_ID,
+    );
+    if windows == nil {
+        return None;
+    }
+
+    let mut found = None;
+    for i in 0..CFArrayGetCount(windows) {
+        let info = CFArrayGetValueAtIndex(windows, i);
+        if window_info_number(info, K_CG_WINDOW_OWNER_PID) == Some(pid as i64)
+            && window_info_number(info, K_CG_WINDOW_LAYER) == Some(0)
+        {
+            found = window_info_number(info, K_CG_WINDOW_NUMBER).map(|number| FrontWindow {
+                number: number as u32,
+                center: window_center(info).unwrap_or(NSPoint::new(0.0, 0.0)),
+            });
+            break;
+        }
+    }
+
+    CFRelease(windows);
+    found
+}
+
+/// Returns the name of the display containing `point`, e.g. "DELL U2720Q".
+unsafe fn display_name_at(point: NSPoint) -> Option<String> {
+    let mut display: u32 = 0;
+    let mut count: u32 = 0;
+    if CGGetDisplaysWithPoint(point, 1, &mut display, &mut count) != 0 || count == 0 {
+        return None;
+    }
+
+    let screens: id = msg_send![class!(NSScreen), screens];
+    let screen_count: usize = msg_send![screens, count];
+    let number_key = NSString::alloc(nil).init_str("NSScreenNumber");
+
+    for i in 0..screen_count {
+        let screen: id = msg_send![screens, objectAtIndex: i];
+        let description: id = msg_send![screen, deviceDescription];
+        let number: id = msg_send![description, objectForKey: number_key];
+        if number == nil {
+            continue;
+        }
+        let screen_display: u32 = msg_send![number, unsignedIntValue];
+        if screen_display != display {
+            continue;
+        }
+
+        let name: id = msg_send![screen, localizedName];
+        if name != nil {
+            let c_string: *const c_char = msg_send![name, UTF8String];
+            if !c_string.is_null() {
+                return Some(CStr::from_ptr(c_string).to_string_lossy().to_string());
+            }
+        }
     }
+
+    Some(format!("Display {}", display))
+}
+
+/// Returns the 1-based number of the active Space (virtual desktop), counting the
+/// Spaces of all displays in Mission Control order.
+unsafe fn active_space_number() -> Option<u32> {
+    let connection = CGSMainConnectionID();
+    let active = CGSGetActiveSpace(connection);
+    let displays = CGSCopyManagedDisplaySpaces(connection);
+    if displays == nil {
+        return None;
+    }
+
+    let spaces_key = NSString::alloc(nil).init_str("Spaces");
+    let mut number = 0;
+    let mut found = None;
+
+    'displays: for i in 0..CFArrayGetCount(displays) {
+        let display = CFArrayGetValueAtIndex(displays, i);
+        let spaces: id = msg_send![display, objectForKey: spaces_key];
+        if spaces == nil {
+            continue;
+        }
+
+        let space_count: usize = msg_send![spaces, count];
+        for j in 0..space_count {
+            let space: id = msg_send![spaces, objectAtIndex: j];
+            number += 1;
+            if window_info_number(space, "id64") == Some(active as i64) {
+                found = Some(number);
+                break 'displays;
+            }
+        }
+    }
+
+    CFRelease(displays);
+    found
+}
+
+/// Returns the numbers of all windows that currently exist, including minimized ones.
+///
+/// # Safety
+///
+/// This function is unsafe because it calls CoreGraphics and Objective-C FFI functions.
+pub unsafe fn live_window_ids() -> HashSet<u32> {
+    let mut ids = HashSet::new();
+    let windows = CGWindowListCopyWindowInfo(
+        K_CG_WINDOW_LIST_OPTION_ALL | K_CG_WINDOW_LIST_EXCLUDE_DESKTOP_ELEMENTS,
+        K_CG_NULL_WINDOW_ID,
+    );
+    if windows == nil {
+        return ids;
+    }
+
+    for i in 0..CFArrayGetCount(windows) {
+        let info = CFArrayGetValueAtIndex(windows, i);
+        if let Some(number) = window_info_number(info, K_CG_WINDOW_NUMBER) {
+            ids.insert(number as u32);
+        }
+    }
+
+    CFRelease(windows);
+    ids
 }
 
 /// Updates the global state with information about the current keyboard layout.
@@ -43,13 +370,15 @@ pub unsafe fn update_active_window() {
 ///
 /// This function is unsafe because:
 /// 1. It calls numerous FFI functions (TIS... and CF...) to interact with macOS APIs.
-/// 2. It writes to the `static mut` variable `state::CURRENT_KEYBOARD_LAYOUT`.
+/// 2. It writes to the `static mut` variables `state::CURRENT_KEYBOARD_LAYOUT` and
+///    `state::CURRENT_INPUT_SOURCE_ID`.
 ///     The caller must ensure that access to `state` (specifically
 ///     `state::CURRENT_KEYBOARD_LAYOUT`) is synchronized if the application is or becomes multi-threaded.
 pub unsafe fn update_keyboard_layout() {
     let input_source = TISCopyCurrentKeyboardInputSource();
     if input_source == nil {
         state::CURRENT_KEYBOARD_LAYOUT = Some("Unknown".to_string());
+        state::CURRENT_INPUT_SOURCE_ID = None;
         return;
     }
 
@@ -58,6 +387,7 @@ pub unsafe fn update_keyboard_layout() {
 
     let source_id = TISGetInputSourceProperty(input_source, id_key);
     let mut layout_info = String::new();
+    let mut input_source_id = None;
 
     if source_id != nil && CFGetTypeID(source_id) == CFStringGetTypeID() {
         let length = CFStringGetLength(source_id);
@@ -73,6 +403,7 @@ pub unsafe fn update_keyboard_layout() {
             ) {
                 if let Ok(id_str) = CStr::from_ptr(buffer.as_ptr() as *const c_char).to_str() {
                     layout_info = id_str.to_string();
+                    input_source_id = Some(id_str.to_string());
                 }
             }
         }
@@ -109,4 +440,5 @@ pub unsafe fn update_keyboard_layout() {
     }
 
     state::CURRENT_KEYBOARD_LAYOUT = Some(layout_info);
+    state::CURRENT_INPUT_SOURCE_ID = input_source_id;
 }
@@ -1,11 +1,17 @@
-use crate::core::{monitor, switcher};
+use crate::core::config::{MemoryMode, ProjectFiles};
+use crate::core::macos_api::K_TIS_NOTIFY_ENABLED_KEYBOARD_INPUT_SOURCES_CHANGED;
+use crate::core::rules::Rule;
+use crate::core::schedule::{self, LocalTime};
+use crate::core::{control, monitor, sources, switcher};
 use crate::state;
 
-use cocoa::base::{id, nil};
+use cocoa::base::{id, nil, NO, YES};
 use cocoa::foundation::{NSAutoreleasePool, NSString};
 use objc::declare::ClassDecl;
 use objc::runtime::{Object, Sel};
 use objc::{class, msg_send, sel, sel_impl};
+use serde_json::json;
+use std::time::Duration;
 
 fn create_observer_class() -> *const objc::runtime::Class {
     let superclass = class!(NSObject);
@@ -21,23 +27,171 @@ fn create_observer_class() -> *const objc::runtime::Class {
             sel!(keyboardChanged:),
             keyboard_changed_callback as extern "C" fn(&Object, Sel, id),
         );
+
+        decl.add_method(
+            sel!(spaceChanged:),
+            space_changed_callback as extern "C" fn(&Object, Sel, id),
+        );
+
+        decl.add_method(
+            sel!(sourcesChanged:),
+            sources_changed_callback as extern "C" fn(&Object, Sel, id),
+        );
+
+        decl.add_method(
+            sel!(focusCheck:),
+            focus_check_callback as extern "C" fn(&Object, Sel, id),
+        );
+
+        decl.add_method(
+            sel!(focusSettled:),
+            focus_settled_callback as extern "C" fn(&Object, Sel, id),
+        );
+
+        decl.add_method(
+            sel!(switchVerify:),
+            switch_verify_callback as extern "C" fn(&Object, Sel, id),
+        );
+
+        decl.add_method(
+            sel!(switchRetry:),
+            switch_retry_callback as extern "C" fn(&Object, Sel, id),
+        );
+
+        decl.add_method(
+            sel!(scheduleBoundary:),
+            schedule_boundary_callback as extern "C" fn(&Object, Sel, id),
+        );
+
+        decl.add_method(
+            sel!(controlRequest:),
+            control_request_callback as extern "C" fn(&Object, Sel, id),
+        );
     }
 
     decl.register()
 }
 
-extern "C" fn app_changed_callback(_self: &Object, _cmd: Sel, _notification: id) {
+/// Seconds between checks for focus moving to another window of the same application,
+/// a sheet or popover, an overlay panel, another foreground process of a terminal or
+/// another output.
+const FOCUS_CHECK_INTERVAL: f64 = 0.5;
+
+unsafe fn apply_layout_for_focus() {
+    switcher::reset_focus_state();
+    monitor::update_keyboard_layout();
+    switcher::forget_closed_windows();
+    switcher::check_and_switch_layout_by_rules();
+    switcher::remember_current_layout();
+}
+
+/// An application activation whose layout decision waits for the dwell time to pass.
+pub struct PendingFocus {
+    /// One-shot timer that fires when the dwell time has passed.
+    pub timer: id,
+    /// Application focused before the first activation of the burst.
+    pub previous_app: Option<String>,
+}
+
+unsafe fn apply_layout_for_app(previous_app: Option<&str>) {
+    if switcher::track_overlay(previous_app) {
+        switcher::remember_current_layout();
+    } else {
+        apply_layout_for_focus();
+    }
+
+    if let (Some(app), Some(layout)) = (
+        &*std::ptr::addr_of!(state::CURRENT_APP),
+        &*std::ptr::addr_of!(state::CURRENT_KEYBOARD_LAYOUT),
+    ) {
+        println!("Active window: {} | Layout: {}", app, layout);
+        control::emit("focus_changed", json!({ "app": app, "layout": layout }));
+    }
+}
+
+fn dwell_time() -> Option<f64> {
+    unsafe {
+        (*std::ptr::addr_of!(state::CONFIG))
+            .as_ref()
+            .and_then(|config| config.dwell)
+            .map(|dwell| dwell.as_secs_f64())
+    }
+}
+
+/// Postpones the layout decision until focus has stayed put for `dwell` seconds.
+/// Activations during the wait restart it, so a burst of them leads to one decision.
+unsafe fn defer_layout_for_app(observer: id, previous_app: Option<String>, dwell: f64) {
+    let previous_app = match (*std::ptr::addr_of_mut!(state::PENDING_FOCUS)).take() {
+        Some(pending) => {
+            let _: () = msg_send![pending.timer, invalidate];
+            pending.previous_app
+        }
+        None => previous_app,
+    };
+
+    let timer: id = msg_send![class!(NSTimer),
+        scheduledTimerWithTimeInterval: dwell
+        target: observer
+        selector: sel!(focusSettled:)
+        userInfo: nil
+        repeats: NO
+    ];
+    state::PENDING_FOCUS = Some(PendingFocus {
+        timer,
+        previous_app,
+    });
+}
+
+extern "C" fn app_changed_callback(this: &Object, _cmd: Sel, _notification: id) {
     let _pool = unsafe { NSAutoreleasePool::new(nil) };
     unsafe {
+        let previous_app = (*std::ptr::addr_of!(state::CURRENT_APP)).clone();
         monitor::update_active_window();
-        monitor::update_keyboard_layout();
-        switcher::check_and_switch_layout_by_rules();
+        // The focus check or a Space change may have handled this activation already.
+        if *std::ptr::addr_of!(state::CURRENT_APP) == previous_app {
+            return;
+        }
 
-        if let (Some(app), Some(layout)) = (
+        match dwell_time() {
+            Some(dwell) => defer_layout_for_app(this as *const Object as id, previous_app, dwell),
+            None => apply_layout_for_app(previous_app.as_deref()),
+        }
+    }
+}
+
+extern "C" fn focus_settled_callback(_self: &Object, _cmd: Sel, _timer: id) {
+    let _pool = unsafe { NSAutoreleasePool::new(nil) };
+    unsafe {
+        let previous_app = match (*std::ptr::addr_of_mut!(state::PENDING_FOCUS)).take() {
+            Some(pending) => pending.previous_app,
+            None => return,
+        };
+
+        monitor::update_active_window();
+        apply_layout_for_app(previous_app.as_deref());
+    }
+}
+
+extern "C" fn space_changed_callback(_self: &Object, _cmd: Sel, _notification: id) {
+    let _pool = unsafe { NSAutoreleasePool::new(nil) };
+    unsafe {
+        let previous_app = (*std::ptr::addr_of!(state::CURRENT_APP)).clone();
+        monitor::update_active_window();
+        if *std::ptr::addr_of!(state::CURRENT_APP) != previous_app {
+            apply_layout_for_app(previous_app.as_deref());
+        } else {
+            apply_layout_for_focus();
+        }
+
+        if let (Some(workspace), Some(app), Some(layout)) = (
+            &*std::ptr::addr_of!(state::CURRENT_WORKSPACE),
             &*std::ptr::addr_of!(state::CURRENT_APP),
             &*std::ptr::addr_of!(state::CURRENT_KEYBOARD_LAYOUT),
         ) {
-            println!("Active window: {} | Layout: {}", app, layout);
+            println!(
+                "Workspace changed: {} | App: {} | Layout: {}",
+                workspace, app, layout
+            );
         }
     }
 }
@@ -45,18 +199,226 @@ extern "C" fn app_changed_callback(_self: &Object, _cmd: Sel, _notification: id)
 extern "C" fn keyboard_changed_callback(_self: &Object, _cmd: Sel, _notification: id) {
     let _pool = unsafe { NSAutoreleasePool::new(nil) };
     unsafe {
+        let previous_source_id = (*std::ptr::addr_of!(state::CURRENT_INPUT_SOURCE_ID)).clone();
         monitor::update_keyboard_layout();
+        switcher::verify_pending_switch(false);
+        let manual = switcher::is_manual_switch(previous_source_id.as_deref());
+        if manual {
+            switcher::cancel_pending_switch();
+            if !switcher::enforce_rule_layout() {
+                switcher::start_manual_override();
+            }
+        }
+        switcher::remember_current_layout();
 
         if let (Some(app), Some(layout)) = (
             &*std::ptr::addr_of!(state::CURRENT_APP),
             &*std::ptr::addr_of!(state::CURRENT_KEYBOARD_LAYOUT),
         ) {
             println!("Layout changed: {} | App: {}", layout, app);
+            control::emit(
+                "layout_changed",
+                json!({
+                    "app": app,
+                    "layout": layout,
+                    "input_source": *std::ptr::addr_of!(state::CURRENT_INPUT_SOURCE_ID),
+                    "manual": manual,
+                }),
+            );
         }
     }
 }
+
+extern "C" fn sources_changed_callback(_self: &Object, _cmd: Sel, _notification: id) {
+    let _pool = unsafe { NSAutoreleasePool::new(nil) };
+    unsafe {
+        println!("Enabled input sources changed, reloading the list");
+        sources::invalidate();
+    }
+}
+
+extern "C" fn focus_check_callback(_self: &Object, _cmd: Sel, _timer: id) {
+    let _pool = unsafe { NSAutoreleasePool::new(nil) };
+    unsafe {
+        if (*std::ptr::addr_of!(state::PENDING_FOCUS)).is_some() {
+            return;
+        }
+
+        let previous_app = (*std::ptr::addr_of!(state::CURRENT_APP)).clone();
+        let previous_window = state::CURRENT_WINDOW;
+        let previous_role = (*std::ptr::addr_of!(state::CURRENT_WINDOW_ROLE)).clone();
+        let previous_process = (*std::ptr::addr_of!(state::CURRENT_PROCESS)).clone();
+        let previous_directory = (*std::ptr::addr_of!(state::CURRENT_DIRECTORY)).clone();
+        let previous_output = (*std::ptr::addr_of!(state::CURRENT_OUTPUT)).clone();
+        let previous_workspace = (*std::ptr::addr_of!(state::CURRENT_WORKSPACE)).clone();
+        monitor::update_active_window();
+
+        // Overlay panels open and close without activation notifications, and an
+        // activation can show up here before its notification does. Either way the
+        // application changed, so overlays are tracked like on activation.
+        if *std::ptr::addr_of!(state::CURRENT_APP) != previous_app {
+            apply_layout_for_app(previous_app.as_deref());
+            return;
+        }
+
+        let process = &*std::ptr::addr_of!(state::CURRENT_PROCESS);
+        let role = &*std::ptr::addr_of!(state::CURRENT_WINDOW_ROLE);
+        let window_changed = state::CURRENT_WINDOW != previous_window;
+        let role_changed = *role != previous_role;
+        let process_changed = *process != previous_process
+            || *std::ptr::addr_of!(state::CURRENT_DIRECTORY) != previous_directory;
+        let desktop_changed = *std::ptr::addr_of!(state::CURRENT_OUTPUT) != previous_output
+            || *std::ptr::addr_of!(state::CURRENT_WORKSPACE) != previous_workspace;
+        if !window_changed && !role_changed && !process_changed && !desktop_changed {
+            return;
+        }
+
+        apply_layout_for_focus();
+
+        if let (Some(app), Some(layout)) = (
+            &*std::ptr::addr_of!(state::CURRENT_APP),
+            &*std::ptr::addr_of!(state::CURRENT_KEYBOARD_LAYOUT),
+        ) {
+            match (window_changed, state::CURRENT_WINDOW, role, process) {
+                (true, Some(window), Some(role), _) => println!(
+                    "Active window: {} #{} ({}) | Layout: {}",
+                    app, window, role, layout
+                ),
+                (true, Some(window), None, _) => {
+                    println!("Active window: {} #{} | Layout: {}", app, window, layout)
+                }
+                (false, _, Some(role), _) if role_changed => {
+                    println!("Active window: {} ({}) | Layout: {}", app, role, layout)
+                }
+                (_, _, _, Some(process)) if process_changed => println!(
+                    "Foreground process: {} ({}) | App: {} | Layout: {}",
+                    process.name, process.command_line, app, layout
+                ),
+                _ => println!(
+                    "Active window: {} | Workspace: {} | Output: {} | Layout: {}",
+                    app,
+                    (*std::ptr::addr_of!(state::CURRENT_WORKSPACE))
+                        .as_deref()
+                        .unwrap_or("unknown"),
+                    (*std::ptr::addr_of!(state::CURRENT_OUTPUT))
+                        .as_deref()
+                        .unwrap_or("unknown"),
+                    layout
+                ),
+            }
+        }
+    }
+}
+
+/// Starts a one-shot timer that checks, after `delay`, whether a switch we requested
+/// took effect in case no change notification confirms it first.
+///
+/// # Safety
+///
+/// This function is unsafe because it calls Objective-C methods via `msg_send!` and
+/// reads `state::OBSERVER`.
+pub unsafe fn schedule_switch_verification(delay: Duration) {
+    if let Some(observer) = state::OBSERVER {
+        let _: id = msg_send![class!(NSTimer),
+            scheduledTimerWithTimeInterval: delay.as_secs_f64()
+            target: observer
+            selector: sel!(switchVerify:)
+            userInfo: nil
+            repeats: NO
+        ];
+    }
+}
+
+extern "C" fn switch_verify_callback(_self: &Object, _cmd: Sel, _timer: id) {
+    let _pool = unsafe { NSAutoreleasePool::new(nil) };
+    unsafe {
+        if (*std::ptr::addr_of!(state::PENDING_SWITCH)).is_none() {
+            return;
+        }
+
+        monitor::update_keyboard_layout();
+        switcher::verify_pending_switch(true);
+        switcher::remember_current_layout();
+    }
+}
+
+/// Starts a one-shot timer for the next attempt at a failed switch.
+///
+/// # Safety
+///
+/// This function is unsafe because it calls Objective-C methods via `msg_send!` and
+/// reads `state::OBSERVER`.
+pub unsafe fn schedule_switch_retry(delay: Duration) {
+    if let Some(observer) = state::OBSERVER {
+        let _: id = msg_send![class!(NSTimer),
+            scheduledTimerWithTimeInterval: delay.as_secs_f64()
+            target: observer
+            selector: sel!(switchRetry:)
+            userInfo: nil
+            repeats: NO
+        ];
+    }
+}
+
+extern "C" fn control_request_callback(_self: &Object, _cmd: Sel, _object: id) {
+    let _pool = unsafe { NSAutoreleasePool::new(nil) };
+    unsafe {
+        control::handle_calls();
+    }
+}
+
+extern "C" fn switch_retry_callback(_self: &Object, _cmd: Sel, _timer: id) {
+    let _pool = unsafe { NSAutoreleasePool::new(nil) };
+    unsafe {
+        monitor::update_keyboard_layout();
+        switcher::retry_switch();
+    }
+}
+
+/// Starts a one-shot timer for the next moment a rule schedule starts or stops
+/// matching, if any rule has a schedule, replacing the one already waiting.
+unsafe fn schedule_boundary_timer(observer: id) {
+    if let Some(timer) = (*std::ptr::addr_of_mut!(state::SCHEDULE_TIMER)).take() {
+        let _: () = msg_send![timer, invalidate];
+    }
+
+    let wait = (*std::ptr::addr_of!(state::CONFIG))
+        .as_ref()
+        .and_then(|config| {
+            schedule::time_until_next_boundary(
+                config.rules.iter().map(|rule| &rule.schedule),
+                &LocalTime::now(),
+            )
+        });
+
+    if let Some(wait) = wait {
+        let timer: id = msg_send![class!(NSTimer),
+            scheduledTimerWithTimeInterval: wait.as_secs_f64()
+            target: observer
+            selector: sel!(scheduleBoundary:)
+            userInfo: nil
+            repeats: NO
+        ];
+        state::SCHEDULE_TIMER = Some(timer);
+    }
+}
+
+extern "C" fn schedule_boundary_callback(this: &Object, _cmd: Sel, _timer: id) {
+    let _pool = unsafe { NSAutoreleasePool::new(nil) };
+    unsafe {
+        // The timer is done once it fires, so there is nothing left to invalidate.
+        state::SCHEDULE_TIMER = None;
+        monitor::update_keyboard_layout();
+        switcher::check_and_switch_layout_by_rules();
+        switcher::remember_current_layout();
+
+        schedule_boundary_timer(this as *const Object as id);
+    }
+}
+
 /// Creates an observer and subscribes it to system notifications for application
-/// activation and keyboard layout changes.
+/// activation, keyboard layout changes and changes to the enabled input sources, then
+/// sets up what the config needs with `update_watchers`.
 ///
 /// # Safety
 ///
@@ -69,6 +431,7 @@ extern "C" fn keyboard_changed_callback(_self: &Object, _cmd: Sel, _notification
 pub unsafe fn setup_observers() {
     let observer_class = create_observer_class();
     let observer: id = msg_send![observer_class, new];
+    state::OBSERVER = Some(observer);
 
     let workspace: id = msg_send![class!(NSWorkspace), sharedWorkspace];
     let notification_center: id = msg_send![workspace, notificationCenter];
@@ -101,4 +464,86 @@ pub unsafe fn setup_observers() {
         name: tis_notification_name
         object: nil
     ];
+
+    let distributed_center: id = msg_send![class!(NSDistributedNotificationCenter), defaultCenter];
+    let sources_notification_name =
+        NSString::alloc(nil).init_str(K_TIS_NOTIFY_ENABLED_KEYBOARD_INPUT_SOURCES_CHANGED);
+    let _: () = msg_send![distributed_center,
+        addObserver: observer
+        selector: sel!(sourcesChanged:)
+        name: sources_notification_name
+        object: nil
+    ];
+
+    update_watchers();
+}
+
+/// Brings the watching that depends on the config in line with the current one: the
+/// Space change notification for workspace rules, a polling timer for changes that have
+/// no notification (another window or window type, overlay panel, output, terminal
+/// process or working directory) and a one-shot timer for the next rule schedule
+/// boundary. Starts what is newly needed and stops what no longer is, so it can be
+/// called again after the config changes.
+///
+/// # Safety
+///
+/// This function is unsafe because it calls Objective-C methods via `msg_send!` and
+/// reads and writes the global state. It must be called from the main thread.
+pub unsafe fn update_watchers() {
+    let Some(observer) = state::OBSERVER else {
+        return;
+    };
+
+    let needs_focus_check = (*std::ptr::addr_of!(state::CONFIG))
+        .as_ref()
+        .is_some_and(|config| {
+            config.memory == MemoryMode::Window
+                || config.rules.iter().any(Rule::has_process_condition)
+                || config.rules.iter().any(Rule::has_desktop_condition)
+                || config.rules.iter().any(Rule::has_window_condition)
+                || config.project_files != ProjectFiles::Off
+                || !config.overlays.is_empty()
+        });
+    let has_desktop_rules = (*std::ptr::addr_of!(state::CONFIG))
+        .as_ref()
+        .is_some_and(|config| config.rules.iter().any(Rule::has_desktop_condition));
+    if has_desktop_rules != state::WATCHING_SPACES {
+        let workspace: id = msg_send![class!(NSWorkspace), sharedWorkspace];
+        let notification_center: id = msg_send![workspace, notificationCenter];
+        let space_notification_name =
+            NSString::alloc(nil).init_str("NSWorkspaceActiveSpaceDidChangeNotification");
+        if has_desktop_rules {
+            let _: () = msg_send![notification_center,
+                addObserver: observer
+                selector: sel!(spaceChanged:)
+                name: space_notification_name
+                object: nil
+            ];
+        } else {
+            let _: () = msg_send![notification_center,
+                removeObserver: observer
+                name: space_notification_name
+                object: nil
+            ];
+        }
+        state::WATCHING_SPACES = has_desktop_rules;
+    }
+
+    schedule_boundary_timer(observer);
+
+    let focus_check_timer = (*std::ptr::addr_of!(state::FOCUS_CHECK_TIMER)).is_some();
+    if needs_focus_check && !focus_check_timer {
+        let timer: id = msg_send![class!(NSTimer),
+            scheduledTimerWithTimeInterval: FOCUS_CHECK_INTERVAL
+            target: observer
+            selector: sel!(focusCheck:)
+            userInfo: nil
+            repeats: YES
+        ];
+        state::FOCUS_CHECK_TIMER = Some(timer);
+    } else if !needs_focus_check {
+        if let Some(timer) = (*std::ptr::addr_of_mut!(state::FOCUS_CHECK_TIMER)).take() {
+            let _: () = msg_send![timer, invalidate];
+        }
+    }
 }
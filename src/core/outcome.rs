@@ -0,0 +1,143 @@
+use std::fmt;
+use std::time::{Duration, SystemTime};
+
+/// Attempts made for one switch request, the first one included.
+pub const MAX_SWITCH_ATTEMPTS: u32 = 3;
+/// Wait before the first retry; it doubles with every further attempt.
+const RETRY_BASE_DELAY: Duration = Duration::from_millis(100);
+
+/// What a switch request asks for.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SwitchTarget {
+    /// A layout code or name from the config, e.g. `EN`.
+    Layout(String),
+    /// An exact input source id, e.g. `com.apple.keylayout.US`.
+    InputSource(String),
+}
+
+impl fmt::Display for SwitchTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SwitchTarget::Layout(layout) => write!(f, "layout '{}'", layout),
+            SwitchTarget::InputSource(source_id) => write!(f, "input source '{}'", source_id),
+        }
+    }
+}
+
+/// How a switch request ended.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SwitchOutcome {
+    /// The input source with this id was selected. Returned as soon as the system
+    /// accepts the selection; it is reported as `VerifyTimeout` later if the layout
+    /// never actually changes.
+    Switched(String),
+    /// The input source with this id was already active; nothing was done.
+    AlreadyActive(String),
+    /// No installed input source matches the target.
+    NotInstalled,
+    /// The input source with this id matches, but is not enabled in System Settings.
+    NotEnabled(String),
+    /// The source was selected, but its input method has no input mode with this id.
+    NoInputMode(String),
+    /// The system refused the selection with this status code.
+    Rejected(i32),
+    /// The selection was accepted, but the layout did not change in time.
+    VerifyTimeout,
+}
+
+impl SwitchOutcome {
+    pub fn is_failure(&self) -> bool {
+        !matches!(
+            self,
+            SwitchOutcome::Switched(_) | SwitchOutcome::AlreadyActive(_)
+        )
+    }
+
+    /// Whether trying again might help.
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            SwitchOutcome::Rejected(_) | SwitchOutcome::VerifyTimeout
+        )
+    }
+
+    /// Short machine-readable name, e.g. for hook environment variables.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SwitchOutcome::Switched(_) => "switched",
+            SwitchOutcome::AlreadyActive(_) => "already_active",
+            SwitchOutcome::NotInstalled => "not_installed",
+            SwitchOutcome::NotEnabled(_) => "not_enabled",
+            SwitchOutcome::NoInputMode(_) => "no_input_mode",
+            SwitchOutcome::Rejected(_) => "rejected",
+            SwitchOutcome::VerifyTimeout => "verify_timeout",
+        }
+    }
+}
+
+impl fmt::Display for SwitchOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SwitchOutcome::Switched(source_id) => write!(f, "switched to {}", source_id),
+            SwitchOutcome::AlreadyActive(source_id) => write!(f, "{} already active", source_id),
+            SwitchOutcome::NotInstalled => write!(f, "no matching input source is installed"),
+            SwitchOutcome::NotEnabled(source_id) => {
+                write!(f, "{} is installed but not enabled", source_id)
+            }
+            SwitchOutcome::NoInputMode(mode_id) => {
+                write!(f, "the input method has no input mode {}", mode_id)
+            }
+            SwitchOutcome::Rejected(status) => {
+                write!(f, "rejected by the system (status {})", status)
+            }
+            SwitchOutcome::VerifyTimeout => write!(f, "the layout did not change in time"),
+        }
+    }
+}
+
+/// The last switch request that failed for good.
+#[derive(Debug, Clone)]
+pub struct SwitchFailure {
+    pub target: SwitchTarget,
+    pub outcome: SwitchOutcome,
+    /// Application that was focused when the switch was requested.
+    pub app: Option<String>,
+    pub attempts: u32,
+    pub at: SystemTime,
+}
+
+/// How long to wait before making attempt `attempt + 1`, or `None` when `attempt`
+/// was the last one.
+pub fn retry_delay(attempt: u32) -> Option<Duration> {
+    if attempt >= MAX_SWITCH_ATTEMPTS {
+        return None;
+    }
+    Some(RETRY_BASE_DELAY * 2u32.pow(attempt.saturating_sub(1)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retries_back_off_until_the_attempts_are_used_up() {
+        assert_eq!(retry_delay(1), Some(Duration::from_millis(100)));
+        assert_eq!(retry_delay(2), Some(Duration::from_millis(200)));
+        assert_eq!(retry_delay(MAX_SWITCH_ATTEMPTS), None);
+        assert_eq!(retry_delay(MAX_SWITCH_ATTEMPTS + 1), None);
+    }
+
+    #[test]
+    fn only_transient_failures_are_retried() {
+        let switched = SwitchOutcome::Switched("com.apple.keylayout.US".to_string());
+        assert!(!switched.is_failure());
+        assert!(!SwitchOutcome::AlreadyActive(String::new()).is_failure());
+
+        assert!(SwitchOutcome::Rejected(-50).is_retryable());
+        assert!(SwitchOutcome::VerifyTimeout.is_retryable());
+        assert!(SwitchOutcome::NotInstalled.is_failure());
+        assert!(!SwitchOutcome::NotInstalled.is_retryable());
+        assert!(!SwitchOutcome::NotEnabled(String::new()).is_retryable());
+        assert_eq!(SwitchOutcome::VerifyTimeout.as_str(), "verify_timeout");
+    }
+}
@@ -0,0 +1,189 @@
+use crate::core::macos_api::{
+    proc_listchildpids, proc_pidinfo, sysctl, ProcBsdInfo, ProcVnodePathInfo, CTL_KERN,
+    KERN_PROCARGS2, NODEV, PROC_PIDTBSDINFO, PROC_PIDVNODEPATHINFO,
+};
+
+use std::ffi::CStr;
+use std::mem;
+use std::os::raw::c_void;
+use std::path::{Path, PathBuf};
+use std::ptr;
+
+const KERN_ARGMAX: i32 = 8;
+/// Upper bound on the processes visited below a terminal.
+const MAX_DESCENDANTS: usize = 4096;
+const MAX_CHILDREN: usize = 1024;
+
+/// A process running in the foreground of a terminal.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProcessInfo {
+    pub pid: i32,
+    /// Executable name, e.g. `vim`.
+    pub name: String,
+    /// Arguments joined with spaces, starting with the program itself.
+    pub command_line: String,
+    pub working_directory: Option<PathBuf>,
+}
+
+unsafe fn bsd_info(pid: i32) -> Option<ProcBsdInfo> {
+    let mut info: ProcBsdInfo = mem::zeroed();
+    let size = mem::size_of::<ProcBsdInfo>() as i32;
+    let written = proc_pidinfo(
+        pid,
+        PROC_PIDTBSDINFO,
+        0,
+        &mut info as *mut ProcBsdInfo as *mut c_void,
+        size,
+    );
+    if written == size {
+        Some(info)
+    } else {
+        None
+    }
+}
+
+/// Returns the current working directory of `pid`.
+///
+/// # Safety
+///
+/// This function is unsafe because it calls the libproc FFI function `proc_pidinfo`.
+pub unsafe fn working_directory(pid: i32) -> Option<PathBuf> {
+    let mut info: ProcVnodePathInfo = mem::zeroed();
+    let size = mem::size_of::<ProcVnodePathInfo>() as i32;
+    let written = proc_pidinfo(
+        pid,
+        PROC_PIDVNODEPATHINFO,
+        0,
+        &mut info as *mut ProcVnodePathInfo as *mut c_void,
+        size,
+    );
+    if written != size {
+        return None;
+    }
+
+    let path = CStr::from_ptr(info.pvi_cdir_path.as_ptr()).to_string_lossy();
+    if path.is_empty() {
+        None
+    } else {
+        Some(PathBuf::from(path.as_ref()))
+    }
+}
+
+unsafe fn child_pids(pid: i32) -> Vec<i32> {
+    let mut buffer = vec![0i32; MAX_CHILDREN];
+    let count = proc_listchildpids(
+        pid,
+        buffer.as_mut_ptr() as *mut c_void,
+        (buffer.len() * mem::size_of::<i32>()) as i32,
+    );
+    buffer.truncate(count.max(0) as usize);
+    buffer
+}
+
+/// Splits a `KERN_PROCARGS2` buffer into the executable path and `argv`.
+fn parse_procargs(buffer: &[u8]) -> Option<(String, Vec<String>)> {
+    let argc = i32::from_ne_bytes(buffer.get(..4)?.try_into().ok()?) as usize;
+    let mut parts = buffer[4..].split(|byte| *byte == 0);
+    let exec_path = String::from_utf8_lossy(parts.next()?).to_string();
+    let args = parts
+        .filter(|part| !part.is_empty())
+        .take(argc)
+        .map(|part| String::from_utf8_lossy(part).to_string())
+        .collect();
+    Some((exec_path, args))
+}
+
+unsafe fn process_arguments(pid: i32) -> Option<(String, Vec<String>)> {
+    let mut arg_max: i32 = 0;
+    let mut size = mem::size_of::<i32>();
+    let mut mib = [CTL_KERN, KERN_ARGMAX];
+    if sysctl(
+        mib.as_mut_ptr(),
+        mib.len() as u32,
+        &mut arg_max as *mut i32 as *mut c_void,
+        &mut size,
+        ptr::null_mut(),
+        0,
+    ) != 0
+    {
+        return None;
+    }
+
+    let mut buffer = vec![0u8; arg_max.max(0) as usize];
+    let mut size = buffer.len();
+    let mut mib = [CTL_KERN, KERN_PROCARGS2, pid];
+    if sysctl(
+        mib.as_mut_ptr(),
+        mib.len() as u32,
+        buffer.as_mut_ptr() as *mut c_void,
+        &mut size,
+        ptr::null_mut(),
+        0,
+    ) != 0
+    {
+        return None;
+    }
+
+    parse_procargs(&buffer[..size])
+}
+
+/// Finds the process in the foreground of a terminal: among the descendants of
+/// `app_pid`, the leader of a tty's foreground process group. When the terminal has
+/// several tabs, the most recently started foreground process wins.
+///
+/// # Safety
+///
+/// This function is unsafe because it calls libproc and `sysctl` FFI functions.
+pub unsafe fn foreground_process(app_pid: i32) -> Option<ProcessInfo> {
+    let mut pending = vec![app_pid];
+    let mut visited = 0;
+    let mut best: Option<ProcBsdInfo> = None;
+
+    while let Some(pid) = pending.pop() {
+        visited += 1;
+        if visited > MAX_DESCENDANTS {
+            break;
+        }
+
+        for child in child_pids(pid) {
+            pending.push(child);
+
+            let info = match bsd_info(child) {
+                Some(info) => info,
+                None => continue,
+            };
+            if info.e_tdev == NODEV || info.e_tpgid != info.pbi_pid {
+                continue;
+            }
+
+            let started = (info.pbi_start_tvsec, info.pbi_start_tvusec);
+            if best.is_none_or(|best| started > (best.pbi_start_tvsec, best.pbi_start_tvusec)) {
+                best = Some(info);
+            }
+        }
+    }
+
+    let info = best?;
+    let pid = info.pbi_pid as i32;
+    let comm = CStr::from_ptr(info.pbi_comm.as_ptr())
+        .to_string_lossy()
+        .to_string();
+
+    let (name, command_line) = match process_arguments(pid) {
+        Some((exec_path, args)) => {
+            let name = Path::new(&exec_path)
+                .file_name()
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or_else(|| comm.clone());
+            (name, args.join(" "))
+        }
+        None => (comm.clone(), comm),
+    };
+
+    Some(ProcessInfo {
+        pid,
+        name,
+        command_line,
+        working_directory: working_directory(pid),
+    })
+}
@@ -0,0 +1,97 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+pub const PROJECT_FILE_NAME: &str = ".language-handler";
+
+/// Layouts declared by a `.language-handler` file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProjectLayouts {
+    pub path: PathBuf,
+    /// Allowed layouts, the first one is switched to. Never empty.
+    pub layouts: Vec<String>,
+}
+
+/// Reads the `layout = RU, EN` line of a project file. Blank lines, `#`/`;` comments
+/// and other keys are ignored, like in `.editorconfig`.
+fn parse_project_file(content: &str) -> Option<Vec<String>> {
+    content.lines().find_map(|line| {
+        let line = line.trim();
+        if line.starts_with('#') || line.starts_with(';') {
+            return None;
+        }
+
+        let (key, value) = line.split_once('=')?;
+        if !key.trim().eq_ignore_ascii_case("layout") {
+            return None;
+        }
+
+        let layouts: Vec<String> = value
+            .split(',')
+            .map(str::trim)
+            .filter(|layout| !layout.is_empty())
+            .map(str::to_string)
+            .collect();
+        if layouts.is_empty() {
+            None
+        } else {
+            Some(layouts)
+        }
+    })
+}
+
+/// Finds the nearest `.language-handler` file in `directory` or one of its ancestors
+/// that declares a layout.
+pub fn find_project_layouts(directory: &Path) -> Option<ProjectLayouts> {
+    directory.ancestors().find_map(|dir| {
+        let path = dir.join(PROJECT_FILE_NAME);
+        let content = fs::read_to_string(&path).ok()?;
+        let layouts = parse_project_file(&content)?;
+        Some(ProjectLayouts { path, layouts })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn layout_line_is_read() {
+        let content = "# team layout\n; other comment\nroot = true\nLayout = RU, EN\n";
+        assert_eq!(
+            parse_project_file(content),
+            Some(vec!["RU".to_string(), "EN".to_string()])
+        );
+    }
+
+    #[test]
+    fn files_without_layouts_declare_nothing() {
+        assert_eq!(parse_project_file(""), None);
+        assert_eq!(parse_project_file("# layout = RU\n"), None);
+        assert_eq!(parse_project_file("layout = , \n"), None);
+        assert_eq!(parse_project_file("indent = 4\n"), None);
+    }
+
+    #[test]
+    fn nearest_file_with_a_layout_wins() {
+        let root =
+            std::env::temp_dir().join(format!("language-handler-test-{}", std::process::id()));
+        let nested = root.join("project").join("src").join("module");
+        fs::create_dir_all(&nested).unwrap();
+        fs::write(root.join(PROJECT_FILE_NAME), "layout = RU\n").unwrap();
+        fs::write(
+            root.join("project").join(PROJECT_FILE_NAME),
+            "layout = EN, DE\n",
+        )
+        .unwrap();
+        fs::write(nested.join(PROJECT_FILE_NAME), "# no layout here\n").unwrap();
+
+        let found = find_project_layouts(&nested);
+        let outside = find_project_layouts(&root.join("project").join(".."));
+        fs::remove_dir_all(&root).unwrap();
+
+        let found = found.unwrap();
+        assert_eq!(found.path, root.join("project").join(PROJECT_FILE_NAME));
+        assert_eq!(found.layouts, ["EN", "DE"]);
+        assert_eq!(outside.unwrap().layouts, ["RU"]);
+    }
+}
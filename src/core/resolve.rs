@@ -0,0 +1,290 @@
+use crate::core::language::{self, LanguageTag};
+use crate::core::layouts::LayoutDefinition;
+use crate::core::sources::{InputSource, SourceCatalogue};
+
+use std::fmt;
+
+/// How an input source matched a layout target, strongest first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum MatchKind {
+    /// The target is the source's id.
+    ExactId,
+    /// The target is a code or alias from the layout table, whose ids match the source.
+    Alias,
+    /// The source declares the target's language.
+    Language,
+    /// The source's name contains the target, or one of the table's names for it.
+    Name,
+}
+
+impl fmt::Display for MatchKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self {
+            MatchKind::ExactId => "exact id",
+            MatchKind::Alias => "alias",
+            MatchKind::Language => "language",
+            MatchKind::Name => "name",
+        };
+        write!(f, "{}", kind)
+    }
+}
+
+/// An input source that can stand for a layout target.
+#[derive(Debug, Clone, Copy)]
+pub struct Candidate<'a> {
+    pub source: &'a InputSource,
+    pub kind: MatchKind,
+}
+
+/// The language `target` asks for: its layout table entry's, or the target itself as
+/// a tag like `en-GB`.
+fn target_language(definition: Option<&LayoutDefinition>, target: &str) -> Option<LanguageTag> {
+    match definition {
+        Some(definition) => definition.language.as_deref().and_then(LanguageTag::parse),
+        None => LanguageTag::parse(target),
+    }
+}
+
+/// Lists the sources that can stand for `target`, best first: enabled sources before
+/// the others, then by how they matched (exact id, alias, language, name). Within the
+/// same kind the table's order is kept, then the installation order.
+///
+/// `definition` is the layout table entry for `target`, if it has one. Sources that
+/// are not enabled in System Settings are left out unless `include_disabled` is set.
+pub fn resolve<'a>(
+    catalogue: &'a SourceCatalogue,
+    definition: Option<&LayoutDefinition>,
+    target: &str,
+    include_disabled: bool,
+) -> Vec<Candidate<'a>> {
+    let mut candidates: Vec<(Candidate<'a>, usize)> = Vec::new();
+    let mut add = |source: &'a InputSource, kind: MatchKind, position: usize| {
+        if (source.enabled || include_disabled)
+            && !candidates
+                .iter()
+                .any(|(known, _)| known.source.id == source.id)
+        {
+            candidates.push((Candidate { source, kind }, position));
+        }
+    };
+
+    if let Some(source) = catalogue.get(target) {
+        add(source, MatchKind::ExactId, 0);
+    }
+
+    if let Some(definition) = definition {
+        for (position, id) in definition.ids.iter().enumerate() {
+            for source in catalogue
+                .iter()
+                .filter(|source| source.id.starts_with(id.as_str()))
+            {
+                add(source, MatchKind::Alias, position);
+            }
+        }
+    }
+
+    if let Some(tag) = target_language(definition, target) {
+        for (position, source) in catalogue.for_language(&tag).into_iter().enumerate() {
+            add(source, MatchKind::Language, position);
+        }
+    }
+
+    let names: Vec<&str> = match definition {
+        Some(definition) => definition.names.iter().map(String::as_str).collect(),
+        None => vec![target],
+    };
+    for (position, name) in names.iter().enumerate() {
+        for source in catalogue.iter().filter(|source| source.matches_name(name)) {
+            add(source, MatchKind::Name, position);
+        }
+    }
+
+    candidates.sort_by_key(|(candidate, position)| {
+        (!candidate.source.enabled, candidate.kind, *position)
+    });
+    candidates
+        .into_iter()
+        .map(|(candidate, _)| candidate)
+        .collect()
+}
+
+/// Whether the source `source_id` counts as `target` when it is already active, given
+/// the `candidates` that `resolve` found for `target`. Being able to type the target's
+/// language is not enough: the source has to be the target's exact id or one of its
+/// layout table ids, or have the target's language as its main language, at least as
+/// specific as the target (`en` for `EN`, `en-GB` but not `en` for `en-GB`). A name match
+/// only counts when nothing matches the target more strongly.
+pub fn counts_as(
+    candidates: &[Candidate],
+    definition: Option<&LayoutDefinition>,
+    target: &str,
+    source_id: &str,
+) -> bool {
+    let candidate = match candidates
+        .iter()
+        .find(|candidate| candidate.source.id == source_id)
+    {
+        Some(candidate) => candidate,
+        None => return false,
+    };
+
+    match candidate.kind {
+        MatchKind::ExactId | MatchKind::Alias => true,
+        MatchKind::Language => {
+            let range = match target_language(definition, target) {
+                Some(tag) => tag.to_string(),
+                None => return false,
+            };
+            candidate
+                .source
+                .languages
+                .first()
+                .is_some_and(|main| language::tag_matches(&range, main))
+        }
+        MatchKind::Name => candidates.iter().all(|other| other.kind == MatchKind::Name),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::layouts::LayoutTable;
+
+    fn catalogue() -> SourceCatalogue {
+        SourceCatalogue::from_sources(vec![
+            InputSource::for_test("com.apple.keylayout.US", "U.S.", &["en"]),
+            InputSource::for_test("com.apple.keylayout.Russian", "Russian", &["ru"]),
+            InputSource::for_test(
+                "org.example.keylayout.Bilingual",
+                "Bilingual",
+                &["ru", "en"],
+            ),
+            InputSource::for_test("org.example.keylayout.Scottish", "Scottish", &["en-GB"]),
+            InputSource::for_test("org.example.keylayout.DvorakLeft", "Dvorak Left", &[]),
+            InputSource::for_test("org.example.keylayout.USPhonetic", "U.S. Phonetic", &["ru"]),
+        ])
+    }
+
+    fn counts(catalogue: &SourceCatalogue, target: &str, source_id: &str) -> bool {
+        let table = LayoutTable::builtin();
+        let definition = table.lookup(target);
+        let candidates = resolve(catalogue, definition, target, false);
+        counts_as(&candidates, definition, target, source_id)
+    }
+
+    fn ids<'a>(candidates: &[Candidate<'a>]) -> Vec<(&'a str, MatchKind)> {
+        candidates
+            .iter()
+            .map(|candidate| (candidate.source.id.as_str(), candidate.kind))
+            .collect()
+    }
+
+    #[test]
+    fn candidates_are_ranked_by_match_kind() {
+        let catalogue = catalogue();
+        let table = LayoutTable::builtin();
+        let candidates = resolve(&catalogue, table.lookup("EN"), "EN", false);
+        assert_eq!(
+            ids(&candidates),
+            [
+                ("com.apple.keylayout.US", MatchKind::Alias),
+                ("org.example.keylayout.Scottish", MatchKind::Language),
+                ("org.example.keylayout.Bilingual", MatchKind::Language),
+                ("org.example.keylayout.USPhonetic", MatchKind::Name),
+            ]
+        );
+    }
+
+    #[test]
+    fn exact_id_comes_first() {
+        let catalogue = catalogue();
+        let candidates = resolve(&catalogue, None, "org.example.keylayout.Bilingual", false);
+        assert_eq!(
+            ids(&candidates),
+            [("org.example.keylayout.Bilingual", MatchKind::ExactId)]
+        );
+    }
+
+    #[test]
+    fn disabled_sources_are_left_out_or_ranked_last() {
+        let mut disabled = InputSource::for_test("com.apple.keylayout.ABC", "ABC", &["en"]);
+        disabled.enabled = false;
+        let catalogue = SourceCatalogue::from_sources(vec![
+            disabled,
+            InputSource::for_test("org.example.keylayout.Scottish", "Scottish", &["en-GB"]),
+        ]);
+        let table = LayoutTable::builtin();
+
+        let enabled_only = resolve(&catalogue, table.lookup("EN"), "EN", false);
+        assert_eq!(
+            ids(&enabled_only),
+            [("org.example.keylayout.Scottish", MatchKind::Language)]
+        );
+
+        let all = resolve(&catalogue, table.lookup("EN"), "EN", true);
+        assert_eq!(
+            ids(&all),
+            [
+                ("org.example.keylayout.Scottish", MatchKind::Language),
+                ("com.apple.keylayout.ABC", MatchKind::Alias),
+            ]
+        );
+    }
+
+    #[test]
+    fn language_tags_fall_back_to_less_specific_ones() {
+        let catalogue = catalogue();
+        let candidates = resolve(&catalogue, None, "en-GB", false);
+        assert_eq!(ids(&candidates)[0].0, "org.example.keylayout.Scottish");
+        assert!(ids(&candidates).contains(&("com.apple.keylayout.US", MatchKind::Language)));
+        assert!(resolve(&catalogue, None, "fr", false).is_empty());
+    }
+
+    #[test]
+    fn table_ids_and_exact_ids_count() {
+        let catalogue = catalogue();
+        assert!(counts(&catalogue, "EN", "com.apple.keylayout.US"));
+        assert!(counts(&catalogue, "RU", "com.apple.keylayout.Russian"));
+        assert!(counts(
+            &catalogue,
+            "org.example.keylayout.Bilingual",
+            "org.example.keylayout.Bilingual"
+        ));
+        assert!(!counts(&catalogue, "RU", "com.apple.keylayout.US"));
+    }
+
+    #[test]
+    fn secondary_language_does_not_count() {
+        let catalogue = catalogue();
+        assert!(!counts(&catalogue, "EN", "org.example.keylayout.Bilingual"));
+        assert!(!counts(&catalogue, "en", "org.example.keylayout.Bilingual"));
+        assert!(counts(&catalogue, "ru", "org.example.keylayout.Bilingual"));
+    }
+
+    #[test]
+    fn main_language_counts_at_the_requested_specificity() {
+        let catalogue = catalogue();
+        assert!(counts(&catalogue, "EN", "org.example.keylayout.Scottish"));
+        assert!(counts(
+            &catalogue,
+            "en-GB",
+            "org.example.keylayout.Scottish"
+        ));
+        assert!(!counts(&catalogue, "en-GB", "com.apple.keylayout.US"));
+    }
+
+    #[test]
+    fn name_counts_only_without_stronger_matches() {
+        let catalogue = catalogue();
+        assert!(counts(
+            &catalogue,
+            "Dvorak",
+            "org.example.keylayout.DvorakLeft"
+        ));
+        assert!(!counts(
+            &catalogue,
+            "EN",
+            "org.example.keylayout.USPhonetic"
+        ));
+    }
+}
@@ -0,0 +1,403 @@
+use crate::core::accessibility::WindowRole;
+use crate::core::process::ProcessInfo;
+use crate::core::schedule::{LocalTime, Schedule};
+
+use std::fmt;
+
+/// What to do while an application matching a rule is focused.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rule {
+    /// Application the rule applies to; `None` matches every application.
+    pub app: Option<String>,
+    /// Layouts allowed in the application; the first one is switched to when the
+    /// current layout is not among them. Never empty.
+    pub layouts: Vec<String>,
+    /// Switch back immediately whenever the layout is changed away from `layouts`.
+    pub enforce: bool,
+    /// Names of the terminal foreground process the rule applies to; empty matches any.
+    pub processes: Vec<String>,
+    /// Text the foreground process command line has to contain.
+    pub args: Option<String>,
+    /// When the rule is in effect; an empty schedule means always.
+    pub schedule: Schedule,
+    /// Names of the workspaces (virtual desktops) the rule applies on; empty matches any.
+    pub workspaces: Vec<String>,
+    /// Names of the outputs (monitors) the focused window has to be on; empty matches any.
+    pub outputs: Vec<String>,
+    /// Types the focused window has to have one of, e.g. `dialog` or `modal`; empty
+    /// matches any window.
+    pub window_types: Vec<String>,
+}
+
+impl Rule {
+    pub fn new(app: &str, layout: &str) -> Rule {
+        Rule::with_layouts(Some(app.to_string()), vec![layout.to_string()])
+    }
+
+    pub fn with_layouts(app: Option<String>, layouts: Vec<String>) -> Rule {
+        Rule {
+            app,
+            layouts,
+            enforce: false,
+            processes: Vec::new(),
+            args: None,
+            schedule: Schedule::default(),
+            workspaces: Vec::new(),
+            outputs: Vec::new(),
+            window_types: Vec::new(),
+        }
+    }
+
+    /// The layout switched to when the current one is not allowed.
+    pub fn primary_layout(&self) -> &str {
+        &self.layouts[0]
+    }
+
+    pub fn has_process_condition(&self) -> bool {
+        !self.processes.is_empty() || self.args.is_some()
+    }
+
+    pub fn has_schedule_condition(&self) -> bool {
+        !self.schedule.is_empty()
+    }
+
+    pub fn has_desktop_condition(&self) -> bool {
+        !self.workspaces.is_empty() || !self.outputs.is_empty()
+    }
+
+    pub fn has_window_condition(&self) -> bool {
+        !self.window_types.is_empty()
+    }
+
+    fn matches_window(&self, window: Option<&WindowRole>) -> bool {
+        self.window_types.is_empty()
+            || window.is_some_and(|window| {
+                self.window_types
+                    .iter()
+                    .any(|window_type| window.is(window_type))
+            })
+    }
+
+    fn matches_desktop(&self, workspace: Option<&str>, output: Option<&str>) -> bool {
+        let listed = |names: &[String], current: Option<&str>| {
+            names.is_empty()
+                || current.is_some_and(|current| {
+                    names.iter().any(|name| name.eq_ignore_ascii_case(current))
+                })
+        };
+
+        listed(&self.workspaces, workspace) && listed(&self.outputs, output)
+    }
+
+    fn matches_process(&self, process: Option<&ProcessInfo>) -> bool {
+        if !self.has_process_condition() {
+            return true;
+        }
+
+        let process = match process {
+            Some(process) => process,
+            None => return false,
+        };
+
+        let name_matches = self.processes.is_empty()
+            || self
+                .processes
+                .iter()
+                .any(|name| name.eq_ignore_ascii_case(&process.name));
+        let args_match = self
+            .args
+            .as_ref()
+            .is_none_or(|args| process.command_line.contains(args.as_str()));
+
+        name_matches && args_match
+    }
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.app.as_deref().unwrap_or("*"))?;
+        if !self.processes.is_empty() {
+            write!(f, " [process: {}]", self.processes.join(", "))?;
+        }
+        if let Some(ref args) = self.args {
+            write!(f, " [args: {}]", args)?;
+        }
+        if !self.schedule.is_empty() {
+            write!(f, " [{}]", self.schedule)?;
+        }
+        if !self.workspaces.is_empty() {
+            write!(f, " [workspace: {}]", self.workspaces.join(", "))?;
+        }
+        if !self.outputs.is_empty() {
+            write!(f, " [output: {}]", self.outputs.join(", "))?;
+        }
+        if !self.window_types.is_empty() {
+            write!(f, " [window: {}]", self.window_types.join(", "))?;
+        }
+        write!(f, " -> {}", self.layouts.join(", "))?;
+        if self.enforce {
+            write!(f, " (enforced)")?;
+        }
+        Ok(())
+    }
+}
+
+/// What the rules are matched against.
+#[derive(Debug, Clone, Copy)]
+pub struct RuleContext<'a> {
+    pub app: &'a str,
+    /// Foreground process of the focused terminal, if it was resolved.
+    pub process: Option<&'a ProcessInfo>,
+    pub now: LocalTime,
+    /// Name of the current workspace or virtual desktop.
+    pub workspace: Option<&'a str>,
+    /// Name of the output (monitor) the focused window is on.
+    pub output: Option<&'a str>,
+    /// Role of the focused window, if it was resolved.
+    pub window: Option<&'a WindowRole>,
+}
+
+pub fn app_matches(app_name: &str, pattern: &str) -> bool {
+    app_name.contains(pattern) || pattern.contains(app_name)
+}
+
+/// Finds the first rule whose conditions hold, preferring rules written for exactly
+/// this application over partial matches and rules for every application.
+pub fn find_rule<'a>(rules: &'a [Rule], context: &RuleContext) -> Option<&'a Rule> {
+    let conditions_hold = |rule: &&Rule| {
+        rule.matches_process(context.process)
+            && rule.schedule.matches(&context.now)
+            && rule.matches_desktop(context.workspace, context.output)
+            && rule.matches_window(context.window)
+    };
+
+    rules
+        .iter()
+        .filter(|rule| rule.app.as_deref() == Some(context.app))
+        .find(conditions_hold)
+        .or_else(|| {
+            rules
+                .iter()
+                .filter(|rule| {
+                    rule.app
+                        .as_deref()
+                        .is_none_or(|app| app_matches(context.app, app))
+                })
+                .find(conditions_hold)
+        })
+}
+
+/// Whether any rule for `app_name` depends on the terminal foreground process.
+pub fn needs_process(rules: &[Rule], app_name: &str) -> bool {
+    rules.iter().any(|rule| {
+        rule.has_process_condition()
+            && rule
+                .app
+                .as_deref()
+                .is_none_or(|app| app_matches(app_name, app))
+    })
+}
+
+/// Whether any rule for `app_name` depends on the workspace or the output.
+pub fn needs_desktop(rules: &[Rule], app_name: &str) -> bool {
+    rules.iter().any(|rule| {
+        rule.has_desktop_condition()
+            && rule
+                .app
+                .as_deref()
+                .is_none_or(|app| app_matches(app_name, app))
+    })
+}
+
+/// Whether any rule for `app_name` depends on the role of the focused window.
+pub fn needs_window_role(rules: &[Rule], app_name: &str) -> bool {
+    rules.iter().any(|rule| {
+        rule.has_window_condition()
+            && rule
+                .app
+                .as_deref()
+                .is_none_or(|app| app_matches(app_name, app))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::schedule::{self, Date};
+
+    /// Monday 2026-10-19, 10:00.
+    fn monday_morning() -> LocalTime {
+        LocalTime {
+            date: Date {
+                year: 2026,
+                month: 10,
+                day: 19,
+            },
+            weekday: 0,
+            minute_of_day: 10 * 60,
+            second: 0,
+        }
+    }
+
+    fn context(app: &str) -> RuleContext<'_> {
+        RuleContext {
+            app,
+            process: None,
+            now: monday_morning(),
+            workspace: None,
+            output: None,
+            window: None,
+        }
+    }
+
+    fn process(name: &str, command_line: &str) -> ProcessInfo {
+        ProcessInfo {
+            pid: 1,
+            name: name.to_string(),
+            command_line: command_line.to_string(),
+            working_directory: None,
+        }
+    }
+
+    fn layout_of<'a>(rules: &'a [Rule], context: &RuleContext) -> Option<&'a str> {
+        find_rule(rules, context).map(Rule::primary_layout)
+    }
+
+    #[test]
+    fn exact_app_name_wins_over_partial_and_catch_all() {
+        let rules = vec![
+            Rule::with_layouts(None, vec!["EN".to_string()]),
+            Rule::new("Code", "RU"),
+            Rule::new("Visual Studio Code", "DE"),
+        ];
+        assert_eq!(
+            layout_of(&rules, &context("Visual Studio Code")),
+            Some("DE")
+        );
+        assert_eq!(layout_of(&rules, &context("Code - Insiders")), Some("EN"));
+        assert_eq!(layout_of(&rules, &context("Safari")), Some("EN"));
+        assert_eq!(layout_of(&rules[1..], &context("Safari")), None);
+    }
+
+    #[test]
+    fn first_rule_whose_conditions_hold_is_used() {
+        let mut vim = Rule::new("Terminal", "EN");
+        vim.processes = vec!["vim".to_string()];
+        let mut chat = Rule::new("Terminal", "RU");
+        chat.processes = vec!["python3".to_string()];
+        chat.args = Some("chat.py".to_string());
+        let rules = vec![vim, chat, Rule::new("Terminal", "DE")];
+
+        let vim = process("VIM", "vim notes.txt");
+        let chat = process("python3", "python3 chat.py");
+        let other = process("python3", "python3 -m http.server");
+        let with = |process| RuleContext {
+            process,
+            ..context("Terminal")
+        };
+
+        assert_eq!(layout_of(&rules, &with(Some(&vim))), Some("EN"));
+        assert_eq!(layout_of(&rules, &with(Some(&chat))), Some("RU"));
+        assert_eq!(layout_of(&rules, &with(Some(&other))), Some("DE"));
+        assert_eq!(layout_of(&rules, &with(None)), Some("DE"));
+    }
+
+    #[test]
+    fn process_is_only_needed_for_rules_that_use_it() {
+        let mut vim = Rule::new("Terminal", "EN");
+        vim.processes = vec!["vim".to_string()];
+        let rules = vec![vim, Rule::new("Safari", "DE")];
+
+        assert!(needs_process(&rules, "Terminal"));
+        assert!(!needs_process(&rules, "Safari"));
+    }
+
+    #[test]
+    fn schedule_conditions() {
+        let mut evening = Rule::new("Telegram", "RU");
+        evening.schedule.times = vec![schedule::parse_time_window("18:00-23:00").unwrap()];
+        let mut weekdays = Rule::new("Telegram", "EN");
+        weekdays.schedule.weekdays = schedule::parse_weekdays("mon-fri").unwrap();
+        assert!(evening.has_schedule_condition());
+        assert!(!Rule::new("Telegram", "EN").has_schedule_condition());
+        let rules = vec![evening, weekdays];
+
+        assert_eq!(layout_of(&rules, &context("Telegram")), Some("EN"));
+        let sunday_evening = RuleContext {
+            now: LocalTime {
+                weekday: 6,
+                minute_of_day: 20 * 60,
+                ..monday_morning()
+            },
+            ..context("Telegram")
+        };
+        assert_eq!(layout_of(&rules, &sunday_evening), Some("RU"));
+        let sunday_morning = RuleContext {
+            now: LocalTime {
+                weekday: 6,
+                ..monday_morning()
+            },
+            ..context("Telegram")
+        };
+        assert_eq!(layout_of(&rules, &sunday_morning), None);
+    }
+
+    #[test]
+    fn desktop_conditions() {
+        let mut second_desktop = Rule::new("Safari", "RU");
+        second_desktop.workspaces = vec!["2".to_string()];
+        let mut external = Rule::new("Safari", "DE");
+        external.outputs = vec!["DELL U2720Q".to_string()];
+        let rules = vec![second_desktop, external];
+
+        let on = |workspace, output| RuleContext {
+            workspace,
+            output,
+            ..context("Safari")
+        };
+        assert_eq!(layout_of(&rules, &on(Some("2"), None)), Some("RU"));
+        assert_eq!(
+            layout_of(&rules, &on(Some("1"), Some("dell u2720q"))),
+            Some("DE")
+        );
+        assert_eq!(layout_of(&rules, &on(None, None)), None);
+    }
+
+    #[test]
+    fn desktop_is_only_needed_for_rules_that_use_it() {
+        let mut desktop = Rule::with_layouts(None, vec!["RU".to_string()]);
+        desktop.workspaces = vec!["2".to_string()];
+        let rules = vec![Rule::new("Terminal", "EN"), desktop];
+
+        assert!(needs_desktop(&rules, "Safari"));
+        assert!(!needs_desktop(&rules[..1], "Terminal"));
+    }
+
+    #[test]
+    fn window_conditions() {
+        let mut dialog = Rule::new("Safari", "EN");
+        dialog.window_types = vec!["modal".to_string()];
+        let rules = vec![dialog, Rule::new("Safari", "DE")];
+
+        let modal = WindowRole {
+            kind: "dialog".to_string(),
+            modal: true,
+        };
+        let in_dialog = RuleContext {
+            window: Some(&modal),
+            ..context("Safari")
+        };
+        assert_eq!(layout_of(&rules, &in_dialog), Some("EN"));
+        assert_eq!(layout_of(&rules, &context("Safari")), Some("DE"));
+    }
+
+    #[test]
+    fn window_is_only_needed_for_rules_that_use_it() {
+        let mut dialog = Rule::new("Safari", "EN");
+        dialog.window_types = vec!["dialog".to_string()];
+        let rules = vec![Rule::new("Terminal", "EN"), dialog];
+
+        assert!(needs_window_role(&rules, "Safari"));
+        assert!(!needs_window_role(&rules, "Terminal"));
+    }
+}
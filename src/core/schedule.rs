@@ -0,0 +1,402 @@
+use crate::core::macos_api::{localtime_r, time, Tm};
+
+use std::fmt;
+use std::time::Duration;
+
+const MINUTES_PER_DAY: u32 = 24 * 60;
+const DAY_NAMES: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+const FULL_DAY_NAMES: [&str; 7] = [
+    "monday",
+    "tuesday",
+    "wednesday",
+    "thursday",
+    "friday",
+    "saturday",
+    "sunday",
+];
+/// Upper bound on the wait for the next boundary, so that clock and DST changes are
+/// picked up within the hour.
+const MAX_BOUNDARY_WAIT: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Date {
+    pub year: i32,
+    pub month: u32,
+    pub day: u32,
+}
+
+impl fmt::Display for Date {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:04}-{:02}-{:02}", self.year, self.month, self.day)
+    }
+}
+
+/// The current local date and time, as far as schedules are concerned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LocalTime {
+    pub date: Date,
+    /// 0 for Monday through 6 for Sunday.
+    pub weekday: u32,
+    pub minute_of_day: u32,
+    pub second: u32,
+}
+
+impl LocalTime {
+    /// Reads the wall clock in the local timezone.
+    pub fn now() -> LocalTime {
+        unsafe {
+            let now = time(std::ptr::null_mut());
+            let mut tm: Tm = std::mem::zeroed();
+            localtime_r(&now, &mut tm);
+
+            LocalTime {
+                date: Date {
+                    year: tm.tm_year + 1900,
+                    month: (tm.tm_mon + 1) as u32,
+                    day: tm.tm_mday as u32,
+                },
+                weekday: ((tm.tm_wday + 6) % 7) as u32,
+                minute_of_day: (tm.tm_hour * 60 + tm.tm_min) as u32,
+                second: tm.tm_sec as u32,
+            }
+        }
+    }
+}
+
+/// A daily time window in minutes after midnight; `end` before `start` wraps past midnight.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimeWindow {
+    pub start: u32,
+    pub end: u32,
+}
+
+impl TimeWindow {
+    fn contains(&self, minute: u32) -> bool {
+        if self.start <= self.end {
+            self.start <= minute && minute < self.end
+        } else {
+            minute >= self.start || minute < self.end
+        }
+    }
+}
+
+impl fmt::Display for TimeWindow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:02}:{:02}-{:02}:{:02}",
+            self.start / 60,
+            self.start % 60,
+            self.end / 60,
+            self.end % 60
+        )
+    }
+}
+
+/// An inclusive range of dates.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DateRange {
+    pub first: Date,
+    pub last: Date,
+}
+
+impl fmt::Display for DateRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.first == self.last {
+            write!(f, "{}", self.first)
+        } else {
+            write!(f, "{}..{}", self.first, self.last)
+        }
+    }
+}
+
+/// When a rule is in effect. Every condition that is set has to hold; within one
+/// condition, any of the listed windows is enough.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Schedule {
+    pub times: Vec<TimeWindow>,
+    /// Bit `n` set for weekday `n` (0 is Monday); 0 means every day.
+    pub weekdays: u8,
+    pub dates: Vec<DateRange>,
+}
+
+impl Schedule {
+    pub fn is_empty(&self) -> bool {
+        self.times.is_empty() && self.weekdays == 0 && self.dates.is_empty()
+    }
+
+    pub fn matches(&self, now: &LocalTime) -> bool {
+        let time_matches = self.times.is_empty()
+            || self
+                .times
+                .iter()
+                .any(|window| window.contains(now.minute_of_day));
+        let weekday_matches = self.weekdays == 0 || self.weekdays & (1 << now.weekday) != 0;
+        let date_matches = self.dates.is_empty()
+            || self
+                .dates
+                .iter()
+                .any(|range| range.first <= now.date && now.date <= range.last);
+
+        time_matches && weekday_matches && date_matches
+    }
+
+    /// Minutes after midnight at which this schedule can start or stop matching.
+    fn boundaries(&self) -> Vec<u32> {
+        let mut boundaries: Vec<u32> = self
+            .times
+            .iter()
+            .flat_map(|window| [window.start, window.end])
+            .collect();
+        if self.weekdays != 0 || !self.dates.is_empty() {
+            boundaries.push(0);
+        }
+        boundaries
+    }
+}
+
+impl fmt::Display for Schedule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut parts = Vec::new();
+        if !self.times.is_empty() {
+            let times: Vec<String> = self.times.iter().map(|w| w.to_string()).collect();
+            parts.push(format!("time: {}", times.join(", ")));
+        }
+        if self.weekdays != 0 {
+            let days: Vec<&str> = (0..7)
+                .filter(|day| self.weekdays & (1 << day) != 0)
+                .map(|day| DAY_NAMES[day])
+                .collect();
+            parts.push(format!("days: {}", days.join(", ")));
+        }
+        if !self.dates.is_empty() {
+            let dates: Vec<String> = self.dates.iter().map(|r| r.to_string()).collect();
+            parts.push(format!("dates: {}", dates.join(", ")));
+        }
+        write!(f, "{}", parts.join("; "))
+    }
+}
+
+/// How long until any of `schedules` can change whether it matches.
+/// Returns `None` when there is nothing to wait for.
+pub fn time_until_next_boundary<'a>(
+    schedules: impl Iterator<Item = &'a Schedule>,
+    now: &LocalTime,
+) -> Option<Duration> {
+    let minutes = schedules
+        .flat_map(Schedule::boundaries)
+        .map(|boundary| {
+            let ahead = (boundary + MINUTES_PER_DAY - now.minute_of_day) % MINUTES_PER_DAY;
+            if ahead == 0 {
+                MINUTES_PER_DAY
+            } else {
+                ahead
+            }
+        })
+        .min()?;
+
+    let seconds = (minutes * 60).saturating_sub(now.second).max(1);
+    Some(Duration::from_secs(seconds as u64).min(MAX_BOUNDARY_WAIT))
+}
+
+fn parse_clock(value: &str) -> Option<u32> {
+    let (hours, minutes) = value.trim().split_once(':')?;
+    let hours: u32 = hours.parse().ok()?;
+    let minutes: u32 = minutes.parse().ok()?;
+    if hours > 24 || minutes > 59 || (hours == 24 && minutes != 0) {
+        return None;
+    }
+    Some((hours * 60 + minutes) % MINUTES_PER_DAY)
+}
+
+/// Parses `"09:00-18:00"`; `"22:00-07:00"` wraps past midnight.
+pub fn parse_time_window(value: &str) -> Result<TimeWindow, String> {
+    let error = || format!("invalid time window '{}', expected HH:MM-HH:MM", value);
+    let (start, end) = value.split_once('-').ok_or_else(error)?;
+    let window = TimeWindow {
+        start: parse_clock(start).ok_or_else(error)?,
+        end: parse_clock(end).ok_or_else(error)?,
+    };
+    if window.start == window.end {
+        return Err(format!("time window '{}' is empty", value));
+    }
+    Ok(window)
+}
+
+/// Parses an English day name, or an abbreviation of at least three letters.
+fn parse_weekday(value: &str) -> Option<u32> {
+    let value = value.trim().to_lowercase();
+    if value.chars().count() < 3 {
+        return None;
+    }
+    FULL_DAY_NAMES
+        .iter()
+        .position(|name| name.starts_with(value.as_str()))
+        .map(|day| day as u32)
+}
+
+/// Parses a day name (`"mon"`, `"Monday"`) or a range of days (`"mon-fri"`, `"fri-mon"`)
+/// into a weekday bit mask.
+pub fn parse_weekdays(value: &str) -> Result<u8, String> {
+    let error = || format!("invalid day '{}', expected e.g. mon or mon-fri", value);
+    let (first, last) = match value.split_once('-') {
+        Some((first, last)) => (
+            parse_weekday(first).ok_or_else(error)?,
+            parse_weekday(last).ok_or_else(error)?,
+        ),
+        None => {
+            let day = parse_weekday(value).ok_or_else(error)?;
+            (day, day)
+        }
+    };
+
+    let mut mask = 0u8;
+    let mut day = first;
+    loop {
+        mask |= 1 << day;
+        if day == last {
+            break;
+        }
+        day = (day + 1) % 7;
+    }
+    Ok(mask)
+}
+
+fn parse_date(value: &str) -> Option<Date> {
+    let mut parts = value.trim().splitn(3, '-');
+    let date = Date {
+        year: parts.next()?.parse().ok()?,
+        month: parts.next()?.parse().ok()?,
+        day: parts.next()?.parse().ok()?,
+    };
+    if !(1..=12).contains(&date.month) || !(1..=31).contains(&date.day) {
+        return None;
+    }
+    Some(date)
+}
+
+/// Parses `"2026-12-20..2027-01-10"` or a single `"2026-12-31"`.
+pub fn parse_date_range(value: &str) -> Result<DateRange, String> {
+    let error = || {
+        format!(
+            "invalid date range '{}', expected YYYY-MM-DD or YYYY-MM-DD..YYYY-MM-DD",
+            value
+        )
+    };
+    let (first, last) = value.split_once("..").unwrap_or((value, value));
+    let range = DateRange {
+        first: parse_date(first).ok_or_else(error)?,
+        last: parse_date(last).ok_or_else(error)?,
+    };
+    if range.last < range.first {
+        return Err(format!("date range '{}' ends before it starts", value));
+    }
+    Ok(range)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(weekday: u32, hour: u32, minute: u32, second: u32) -> LocalTime {
+        LocalTime {
+            date: Date {
+                year: 2026,
+                month: 10,
+                day: 19 + weekday,
+            },
+            weekday,
+            minute_of_day: hour * 60 + minute,
+            second,
+        }
+    }
+
+    #[test]
+    fn weekdays_accept_names_and_ranges() {
+        assert_eq!(parse_weekdays("mon"), Ok(0b0000001));
+        assert_eq!(parse_weekdays("Sunday"), Ok(0b1000000));
+        assert_eq!(parse_weekdays("tues"), Ok(0b0000010));
+        assert_eq!(parse_weekdays("mon-fri"), Ok(0b0011111));
+        assert_eq!(parse_weekdays("fri-mon"), Ok(0b1110001));
+    }
+
+    #[test]
+    fn weekdays_reject_unknown_names() {
+        assert!(parse_weekdays("пн-пт").is_err());
+        assert!(parse_weekdays("mo").is_err());
+        assert!(parse_weekdays("monxyz").is_err());
+        assert!(parse_weekdays("mon-").is_err());
+        assert!(parse_weekdays("").is_err());
+    }
+
+    #[test]
+    fn time_windows() {
+        let window = parse_time_window("09:00-18:30").unwrap();
+        assert_eq!((window.start, window.end), (9 * 60, 18 * 60 + 30));
+        assert!(window.contains(9 * 60));
+        assert!(!window.contains(18 * 60 + 30));
+
+        let night = parse_time_window("22:00-07:00").unwrap();
+        assert!(night.contains(23 * 60));
+        assert!(night.contains(6 * 60));
+        assert!(!night.contains(12 * 60));
+
+        assert_eq!(parse_time_window("18:00-24:00").unwrap().end, 0);
+        assert!(parse_time_window("09:00-09:00").is_err());
+        assert!(parse_time_window("9-18").is_err());
+        assert!(parse_time_window("09:60-10:00").is_err());
+        assert!(parse_time_window("24:30-10:00").is_err());
+    }
+
+    #[test]
+    fn date_ranges() {
+        let range = parse_date_range("2026-12-24..2027-01-08").unwrap();
+        assert_eq!(range.to_string(), "2026-12-24..2027-01-08");
+        let day = parse_date_range("2026-12-31").unwrap();
+        assert_eq!(day.first, day.last);
+
+        assert!(parse_date_range("2027-01-08..2026-12-24").is_err());
+        assert!(parse_date_range("2026-13-01").is_err());
+        assert!(parse_date_range("2026-12").is_err());
+        assert!(parse_date_range("soon").is_err());
+    }
+
+    #[test]
+    fn next_boundary_is_the_nearest_window_edge() {
+        let schedule = Schedule {
+            times: vec![parse_time_window("09:00-18:00").unwrap()],
+            ..Schedule::default()
+        };
+        let wait = time_until_next_boundary([&schedule].into_iter(), &at(0, 8, 30, 15));
+        assert_eq!(wait, Some(Duration::from_secs(30 * 60 - 15)));
+
+        let wait = time_until_next_boundary([&schedule].into_iter(), &at(0, 17, 59, 0));
+        assert_eq!(wait, Some(Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn next_boundary_is_capped_and_wraps_past_midnight() {
+        let schedule = Schedule {
+            times: vec![parse_time_window("09:00-18:00").unwrap()],
+            ..Schedule::default()
+        };
+        let wait = time_until_next_boundary([&schedule].into_iter(), &at(0, 23, 0, 0));
+        assert_eq!(wait, Some(MAX_BOUNDARY_WAIT));
+
+        let days = Schedule {
+            weekdays: parse_weekdays("sat-sun").unwrap(),
+            ..Schedule::default()
+        };
+        let wait = time_until_next_boundary([&days].into_iter(), &at(4, 23, 59, 30));
+        assert_eq!(wait, Some(Duration::from_secs(30)));
+    }
+
+    #[test]
+    fn no_boundary_without_conditions() {
+        let schedule = Schedule::default();
+        assert_eq!(
+            time_until_next_boundary([&schedule].into_iter(), &at(0, 12, 0, 0)),
+            None
+        );
+    }
+}
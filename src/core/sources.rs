@@ -0,0 +1,335 @@
+use crate::core::language::{self, LanguageTag};
+use crate::core::macos_api::{
+    CFArrayGetCount, CFArrayGetValueAtIndex, CFGetTypeID, CFRelease, CFRetain, CFStringGetTypeID,
+    TISCreateInputSourceList, TISGetInputSourceProperty, K_TIS_PROPERTY_BUNDLE_ID,
+    K_TIS_PROPERTY_INPUT_MODE_ID, K_TIS_PROPERTY_INPUT_SOURCE_ID,
+    K_TIS_PROPERTY_INPUT_SOURCE_IS_ENABLED, K_TIS_PROPERTY_INPUT_SOURCE_IS_ENABLE_CAPABLE,
+    K_TIS_PROPERTY_INPUT_SOURCE_IS_SELECT_CAPABLE, K_TIS_PROPERTY_INPUT_SOURCE_LANGUAGES,
+    K_TIS_PROPERTY_INPUT_SOURCE_TYPE, K_TIS_PROPERTY_LOCALIZED_NAME,
+};
+use crate::state;
+
+use cocoa::base::{id, nil};
+use cocoa::foundation::NSString;
+use objc::{msg_send, sel, sel_impl};
+use std::collections::HashMap;
+use std::ffi::CStr;
+use std::os::raw::c_char;
+use std::rc::Rc;
+
+/// What kind of input source a source is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SourceKind {
+    /// A plain keyboard layout, e.g. U.S. or Russian.
+    Layout,
+    /// An input method, e.g. Pinyin or Kotoeri.
+    InputMethod,
+    /// One input mode of an input method, e.g. Hiragana.
+    InputMode,
+    /// Anything else, such as the emoji palette.
+    Other,
+}
+
+impl SourceKind {
+    fn from_type(source_type: Option<&str>) -> SourceKind {
+        match source_type {
+            Some("TISTypeKeyboardLayout") => SourceKind::Layout,
+            Some("TISTypeKeyboardInputMethodWithoutModes")
+            | Some("TISTypeKeyboardInputMethodModeEnabled") => SourceKind::InputMethod,
+            Some("TISTypeKeyboardInputMode") => SourceKind::InputMode,
+            _ => SourceKind::Other,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SourceKind::Layout => "layout",
+            SourceKind::InputMethod => "input_method",
+            SourceKind::InputMode => "input_mode",
+            SourceKind::Other => "other",
+        }
+    }
+}
+
+/// An installed input source, with the properties layouts are matched against.
+#[derive(Debug)]
+pub struct InputSource {
+    pub id: String,
+    pub name: String,
+    /// Languages the source can type, most important first, e.g. `["ru"]`.
+    pub languages: Vec<String>,
+    pub kind: SourceKind,
+    /// Whether the user has added the source in System Settings; only enabled sources
+    /// can be selected.
+    pub enabled: bool,
+    /// Whether the source can be selected; the parent of an input method's modes
+    /// cannot.
+    pub selectable: bool,
+    /// Whether the source can be enabled programmatically.
+    pub enable_capable: bool,
+    /// For an input mode of an input method, its mode id, e.g.
+    /// `com.apple.inputmethod.Japanese.Katakana`.
+    pub input_mode: Option<String>,
+    /// Bundle of the input method the source belongs to; its modes share it.
+    pub bundle_id: Option<String>,
+    /// Retained `TISInputSourceRef`, released when the catalogue is dropped.
+    source: id,
+}
+
+impl InputSource {
+    pub fn as_raw(&self) -> id {
+        self.source
+    }
+
+    /// An enabled keyboard layout that is not backed by a system input source, for tests.
+    #[cfg(test)]
+    pub fn for_test(id: &str, name: &str, languages: &[&str]) -> InputSource {
+        InputSource {
+            id: id.to_string(),
+            name: name.to_string(),
+            languages: languages
+                .iter()
+                .map(|language| language.to_string())
+                .collect(),
+            kind: SourceKind::Layout,
+            enabled: true,
+            selectable: true,
+            enable_capable: true,
+            input_mode: None,
+            bundle_id: None,
+            source: nil,
+        }
+    }
+
+    /// Whether the name contains `pattern` in any case.
+    pub fn matches_name(&self, pattern: &str) -> bool {
+        self.name.to_uppercase().contains(&pattern.to_uppercase())
+    }
+}
+
+impl Drop for InputSource {
+    fn drop(&mut self) {
+        if self.source != nil {
+            unsafe { CFRelease(self.source) }
+        }
+    }
+}
+
+/// The installed input sources, listed once and kept until the system reports that
+/// the enabled sources changed.
+#[derive(Debug, Default)]
+pub struct SourceCatalogue {
+    sources: Vec<InputSource>,
+    by_id: HashMap<String, usize>,
+    /// Sources by the primary subtag of each language they can type, e.g. `zh`.
+    by_language: HashMap<String, Vec<usize>>,
+}
+
+impl SourceCatalogue {
+    /// Lists the installed input sources.
+    ///
+    /// # Safety
+    ///
+    /// This function is unsafe because it calls TIS and CF FFI functions.
+    pub unsafe fn load() -> SourceCatalogue {
+        let mut catalogue = SourceCatalogue::default();
+
+        let input_sources = TISCreateInputSourceList(nil, true);
+        if input_sources == nil {
+            return catalogue;
+        }
+
+        let id_key = NSString::alloc(nil).init_str(K_TIS_PROPERTY_INPUT_SOURCE_ID);
+        let name_key = NSString::alloc(nil).init_str(K_TIS_PROPERTY_LOCALIZED_NAME);
+        let languages_key = NSString::alloc(nil).init_str(K_TIS_PROPERTY_INPUT_SOURCE_LANGUAGES);
+        let type_key = NSString::alloc(nil).init_str(K_TIS_PROPERTY_INPUT_SOURCE_TYPE);
+        let enabled_key = NSString::alloc(nil).init_str(K_TIS_PROPERTY_INPUT_SOURCE_IS_ENABLED);
+        let selectable_key =
+            NSString::alloc(nil).init_str(K_TIS_PROPERTY_INPUT_SOURCE_IS_SELECT_CAPABLE);
+        let enable_capable_key =
+            NSString::alloc(nil).init_str(K_TIS_PROPERTY_INPUT_SOURCE_IS_ENABLE_CAPABLE);
+        let mode_key = NSString::alloc(nil).init_str(K_TIS_PROPERTY_INPUT_MODE_ID);
+        let bundle_key = NSString::alloc(nil).init_str(K_TIS_PROPERTY_BUNDLE_ID);
+
+        for i in 0..CFArrayGetCount(input_sources) {
+            let source = CFArrayGetValueAtIndex(input_sources, i);
+            if source == nil {
+                continue;
+            }
+
+            let source_id = match cf_string(TISGetInputSourceProperty(source, id_key)) {
+                Some(source_id) => source_id,
+                None => continue,
+            };
+            let name = cf_string(TISGetInputSourceProperty(source, name_key)).unwrap_or_default();
+
+            let mut languages = Vec::new();
+            let language_list = TISGetInputSourceProperty(source, languages_key);
+            if language_list != nil {
+                for j in 0..CFArrayGetCount(language_list) {
+                    if let Some(language) = cf_string(CFArrayGetValueAtIndex(language_list, j)) {
+                        languages.push(language);
+                    }
+                }
+            }
+
+            let source_type = cf_string(TISGetInputSourceProperty(source, type_key));
+            let enabled = cf_bool(TISGetInputSourceProperty(source, enabled_key));
+            let selectable = cf_bool(TISGetInputSourceProperty(source, selectable_key));
+            let enable_capable = cf_bool(TISGetInputSourceProperty(source, enable_capable_key));
+            let input_mode = cf_string(TISGetInputSourceProperty(source, mode_key));
+            let bundle_id = cf_string(TISGetInputSourceProperty(source, bundle_key));
+
+            CFRetain(source);
+            catalogue.insert(InputSource {
+                id: source_id,
+                name,
+                languages,
+                kind: SourceKind::from_type(source_type.as_deref()),
+                enabled,
+                selectable,
+                enable_capable,
+                input_mode,
+                bundle_id,
+                source,
+            });
+        }
+
+        CFRelease(input_sources);
+        catalogue
+    }
+
+    fn insert(&mut self, source: InputSource) {
+        let index = self.sources.len();
+        self.by_id.insert(source.id.clone(), index);
+        for language in &source.languages {
+            let indices = self
+                .by_language
+                .entry(language::primary_language(language))
+                .or_default();
+            if !indices.contains(&index) {
+                indices.push(index);
+            }
+        }
+        self.sources.push(source);
+    }
+
+    /// A catalogue of sources that are not backed by the system, for tests.
+    #[cfg(test)]
+    pub fn from_sources(sources: Vec<InputSource>) -> SourceCatalogue {
+        let mut catalogue = SourceCatalogue::default();
+        for source in sources {
+            catalogue.insert(source);
+        }
+        catalogue
+    }
+
+    pub fn len(&self) -> usize {
+        self.sources.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sources.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &InputSource> {
+        self.sources.iter()
+    }
+
+    pub fn get(&self, source_id: &str) -> Option<&InputSource> {
+        self.by_id.get(source_id).map(|&index| &self.sources[index])
+    }
+
+    /// The source for the input mode `mode_id`, preferring one of the input method
+    /// `bundle_id` and then enabled ones.
+    pub fn input_mode(&self, bundle_id: Option<&str>, mode_id: &str) -> Option<&InputSource> {
+        self.sources
+            .iter()
+            .filter(|source| source.input_mode.as_deref() == Some(mode_id))
+            .min_by_key(|source| {
+                (
+                    bundle_id.is_none() || source.bundle_id.as_deref() != bundle_id,
+                    !source.enabled,
+                )
+            })
+    }
+
+    /// Sources for the language `tag`, trying the tag and then less specific ones
+    /// (`en-GB`, then `en`). At each step, sources whose main language matches come
+    /// before sources that can also type it.
+    pub fn for_language(&self, tag: &LanguageTag) -> Vec<&InputSource> {
+        let candidates: Vec<&InputSource> = self
+            .by_language
+            .get(&tag.language)
+            .into_iter()
+            .flatten()
+            .map(|&index| &self.sources[index])
+            .collect();
+
+        let mut found: Vec<&InputSource> = Vec::new();
+        for range in tag.fallbacks() {
+            let primary = candidates.iter().filter(|source| {
+                source
+                    .languages
+                    .first()
+                    .is_some_and(|language| language::tag_matches(&range, language))
+            });
+            let secondary = candidates.iter().filter(|source| {
+                source
+                    .languages
+                    .iter()
+                    .any(|language| language::tag_matches(&range, language))
+            });
+
+            for source in primary.chain(secondary) {
+                if !found.iter().any(|known| known.id == source.id) {
+                    found.push(source);
+                }
+            }
+        }
+        found
+    }
+}
+
+unsafe fn cf_string(value: id) -> Option<String> {
+    if value == nil || CFGetTypeID(value) != CFStringGetTypeID() {
+        return None;
+    }
+
+    let c_string: *const c_char = msg_send![value, UTF8String];
+    if c_string.is_null() {
+        return None;
+    }
+    Some(CStr::from_ptr(c_string).to_string_lossy().to_string())
+}
+
+unsafe fn cf_bool(value: id) -> bool {
+    value != nil && msg_send![value, boolValue]
+}
+
+/// Returns the catalogue of installed input sources, listing them on first use. A
+/// catalogue that is held on to stays valid after `invalidate`, it is just no longer
+/// the current one.
+///
+/// # Safety
+///
+/// This function is unsafe because it reads and writes `state::SOURCE_CATALOGUE` and
+/// may call TIS FFI functions.
+pub unsafe fn catalogue() -> Rc<SourceCatalogue> {
+    (*std::ptr::addr_of_mut!(state::SOURCE_CATALOGUE))
+        .get_or_insert_with(|| {
+            let catalogue = SourceCatalogue::load();
+            println!("Found {} installed input sources", catalogue.len());
+            Rc::new(catalogue)
+        })
+        .clone()
+}
+
+/// Drops the current catalogue so that it is listed again on next use.
+///
+/// # Safety
+///
+/// This function is unsafe because it writes to `state::SOURCE_CATALOGUE`.
+pub unsafe fn invalidate() {
+    state::SOURCE_CATALOGUE = None;
+}
@@ -1,237 +1,1032 @@
+use crate::core::config::{Config, MemoryMode};
+use crate::core::enforce::EnforcementGuard;
+use crate::core::layouts::{self, LayoutDefinition};
 use crate::core::macos_api::{
-    CFArrayGetCount, CFArrayGetValueAtIndex, CFGetTypeID, CFRelease, CFStringCreateWithCString,
-    CFStringGetCString, CFStringGetLength, CFStringGetTypeID, TISCopyInputSourceForLanguage,
-    TISCreateInputSourceList, TISGetInputSourceProperty, TISSelectInputSource,
-    K_TIS_PROPERTY_INPUT_SOURCE_ID, K_TIS_PROPERTY_LOCALIZED_NAME, K_UTF8_ENCODING,
+    TISEnableInputSource, TISGetInputSourceProperty, TISSelectInputSource,
+    K_TIS_PROPERTY_INPUT_SOURCE_ID,
 };
-use crate::core::monitor;
+use crate::core::memory::{ManualOverride, OverlayReturn};
+use crate::core::outcome::{retry_delay, SwitchFailure, SwitchOutcome, SwitchTarget};
+use crate::core::project::{self, ProjectLayouts};
+use crate::core::resolve::{self, Candidate};
+use crate::core::rules::{app_matches, find_rule, Rule, RuleContext};
+use crate::core::schedule::LocalTime;
+use crate::core::sources::{self, InputSource, SourceCatalogue};
+use crate::core::{control, hooks, monitor, observer};
 use crate::state;
 
-use cocoa::base::nil;
+use cocoa::base::{id, nil};
 use cocoa::foundation::NSString;
+use objc::{msg_send, sel, sel_impl};
+use serde_json::json;
 
 use std::ffi::CStr;
 use std::os::raw::c_char;
 use std::thread;
-use std::time::Duration;
-
-pub fn is_target_layout(current_layout: &str, target_layout: &str) -> bool {
-    match target_layout.to_uppercase().as_str() {
-        "US" | "EN" | "ENGLISH" => {
-            current_layout.contains("U.S.")
-                || current_layout.contains("US")
-                || current_layout.contains("English")
-                || current_layout.contains("com.apple.keylayout.US")
-                || current_layout.contains("ABC")
-        }
-        "RU" | "RUSSIAN" => {
-            current_layout.contains("Russian")
-                || current_layout.contains("Русская")
-                || current_layout.contains("com.apple.keylayout.Russian")
-        }
-        "CN" | "CHINESE" | "PINYIN" | "ZH" => {
-            current_layout.to_uppercase().contains("PINYIN")
-                || current_layout.to_uppercase().contains("SIMPLIFIED")
-                || current_layout.contains("简体")
-                || current_layout.contains("com.apple.keylayout.PinyinSimplified")
-                || current_layout.contains("com.apple.inputmethod.SCIM.Pinyin")
-        }
-        "HI" | "HINDI" | "DEVANAGARI" => {
-            current_layout.to_uppercase().contains("HINDI")
-                || current_layout.to_uppercase().contains("DEVANAGARI")
-                || current_layout.contains("देवनागरी")
-                || current_layout.contains("com.apple.keylayout.Devanagari-QWERTY")
-                || current_layout.contains("com.apple.keylayout.Hindi-QWERTY")
-        }
-        _ => current_layout
-            .to_uppercase()
-            .contains(&target_layout.to_uppercase()),
+use std::time::{Duration, Instant, SystemTime};
+
+/// How long after our own switch a change notification is still attributed to it.
+const OWN_SWITCH_WINDOW: Duration = Duration::from_secs(1);
+/// How long the system gets to report a switch before we stop waiting for it.
+const SWITCH_VERIFY_TIMEOUT: Duration = Duration::from_millis(500);
+/// How often a one-off switch reads the current layout while it waits for the change.
+const SWITCH_POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// An input source we selected whose activation has not been observed yet.
+#[derive(Debug, Clone)]
+pub struct PendingSwitch {
+    pub target: SwitchTarget,
+    pub source_id: String,
+    pub attempt: u32,
+    pub app: Option<String>,
+    pub since: Instant,
+}
+
+/// Another attempt at a switch that failed, waiting for its backoff delay.
+#[derive(Debug, Clone)]
+pub struct PendingRetry {
+    pub target: SwitchTarget,
+    /// Number of the attempt to make, starting at 1.
+    pub attempt: u32,
+    /// Application the switch was requested for.
+    pub app: Option<String>,
+}
+
+/// Looks up `target_layout` in the layout table of the config. The definition belongs
+/// to `state::CONFIG` and must not be kept past a config reload.
+unsafe fn layout_definition(target_layout: &str) -> Option<&'static LayoutDefinition> {
+    (*std::ptr::addr_of!(state::CONFIG))
+        .as_ref()
+        .and_then(|config| config.layouts.lookup(target_layout))
+}
+
+/// The target a user alias from the config stands for, or `target_layout` itself.
+/// Aliases are looked up before the layout table, so they can shadow its codes.
+unsafe fn expand_alias(target_layout: &str) -> &str {
+    (*std::ptr::addr_of!(state::CONFIG))
+        .as_ref()
+        .and_then(|config| config.alias_target(target_layout))
+        .unwrap_or(target_layout)
+}
+
+/// Splits `target_layout`, with a user alias expanded, into the layout and the input
+/// mode id it asks for, if any. Mode names from the layout table are replaced by ids.
+unsafe fn split_target(target_layout: &str) -> (String, Option<String>) {
+    let target_layout = expand_alias(target_layout);
+    let (layout, mode) = layouts::split_input_mode(target_layout);
+    let mode_id = mode.map(|mode| match layout_definition(layout) {
+        Some(definition) => definition.input_mode(mode).to_string(),
+        None => mode.to_string(),
+    });
+    (layout.to_string(), mode_id)
+}
+
+/// Input source ids and names that count as `target_layout`, most specific first.
+/// A pattern matches an id that contains it or a name that contains it in any case.
+/// Layouts missing from the table are matched by their own name.
+unsafe fn search_patterns(target_layout: &str) -> Vec<String> {
+    let (layout, _) = split_target(target_layout);
+    match layout_definition(&layout) {
+        Some(definition) => definition.search_patterns(),
+        None => vec![layout],
+    }
+}
+
+/// Input sources that count as `target_layout`, best first. A user alias is
+/// replaced by its target first, and an input mode is ignored. Then come an exact input
+/// source id, then the layout table's ids for a code or alias, then sources for its
+/// language (or for the target as a language tag like `en-GB`), then names.
+/// Only enabled sources are listed, unless `include_disabled` is set; they then come
+/// first.
+///
+/// # Safety
+///
+/// This function is unsafe because it reads the aliases and layout table from
+/// `state::CONFIG`.
+pub unsafe fn candidate_sources<'a>(
+    catalogue: &'a SourceCatalogue,
+    target_layout: &str,
+    include_disabled: bool,
+) -> Vec<Candidate<'a>> {
+    let (layout, _) = split_target(target_layout);
+    resolve::resolve(
+        catalogue,
+        layout_definition(&layout),
+        &layout,
+        include_disabled,
+    )
+}
+
+unsafe fn enable_missing_layouts() -> bool {
+    (*std::ptr::addr_of!(state::CONFIG))
+        .as_ref()
+        .is_some_and(|config| config.enable_missing_layouts)
+}
+
+/// Checks that every alias in the config resolves to an installed input source and
+/// prints what it stands for, or a warning.
+///
+/// # Safety
+///
+/// This function is unsafe because it reads `static mut` variables in `state` and may
+/// list the installed input sources.
+pub unsafe fn check_aliases() {
+    let config = match (*std::ptr::addr_of!(state::CONFIG)).as_ref() {
+        Some(config) => config,
+        None => return,
+    };
+
+    let catalogue = sources::catalogue();
+    for (alias, target) in &config.aliases {
+        match candidate_sources(&catalogue, alias, true).first() {
+            Some(candidate) if candidate.source.enabled => println!(
+                "Alias '{}' -> {} ({})",
+                alias, candidate.source.id, candidate.kind
+            ),
+            Some(candidate) if config.enable_missing_layouts => println!(
+                "Alias '{}' -> {} (not enabled yet, will be enabled when needed)",
+                alias, candidate.source.id
+            ),
+            Some(candidate) => println!(
+                "Warning: alias '{}' -> {} is installed but not enabled",
+                alias, candidate.source.id
+            ),
+            None => println!(
+                "Warning: alias '{}' ({}) does not match any installed input source",
+                alias, target
+            ),
+        }
+    }
+}
+
+/// Whether the active input source, `current_id` named `current_layout`, counts as
+/// `target_layout` and is in the input mode it asks for. An installed source has to be
+/// the target itself, one of its layout table ids, or a source whose main language is
+/// the target's, as `resolve::counts_as` decides. Otherwise the id and name are
+/// compared with the target's patterns.
+///
+/// # Safety
+///
+/// This function is unsafe because it reads the aliases and layout table from
+/// `state::CONFIG` and may list the installed input sources through TIS FFI calls.
+pub unsafe fn is_target_layout(
+    current_layout: &str,
+    current_id: Option<&str>,
+    target_layout: &str,
+) -> bool {
+    if let Some(current_id) = current_id {
+        let catalogue = sources::catalogue();
+        if let Some(current) = catalogue.get(current_id) {
+            let (layout, mode_id) = split_target(target_layout);
+            let definition = layout_definition(&layout);
+            let candidates = resolve::resolve(&catalogue, definition, &layout, false);
+            return mode_id
+                .is_none_or(|mode_id| current.input_mode.as_deref() == Some(mode_id.as_str()))
+                && resolve::counts_as(&candidates, definition, &layout, current_id);
+        }
+    }
+
+    search_patterns(target_layout).iter().any(|pattern| {
+        current_layout.contains(pattern.as_str())
+            || current_layout
+                .to_uppercase()
+                .contains(&pattern.to_uppercase())
+    })
+}
+
+/// Finds an enabled input source for `target_layout` and selects it, then its input
+/// mode if the target asks for one.
+unsafe fn select_layout(target_layout: &str) -> SwitchOutcome {
+    let outcome = select_layout_source(target_layout);
+    match (split_target(target_layout), &outcome) {
+        (
+            (_, Some(mode_id)),
+            SwitchOutcome::Switched(source_id) | SwitchOutcome::AlreadyActive(source_id),
+        ) => {
+            select_input_mode(source_id, &mode_id, target_layout).unwrap_or_else(|| outcome.clone())
+        }
+        _ => outcome,
+    }
+}
+
+/// Selects the input mode `mode_id` of the input method that `source_id` belongs to,
+/// or returns `None` if `source_id` is that mode already. Modes that are not enabled
+/// are handled like disabled layouts.
+unsafe fn select_input_mode(
+    source_id: &str,
+    mode_id: &str,
+    target_layout: &str,
+) -> Option<SwitchOutcome> {
+    let catalogue = sources::catalogue();
+    let bundle_id = catalogue
+        .get(source_id)
+        .and_then(|source| source.bundle_id.as_deref());
+    match catalogue.input_mode(bundle_id, mode_id) {
+        Some(mode) if mode.id == source_id => None,
+        Some(mode) if mode.enabled => Some(select_source(mode.as_raw())),
+        Some(mode) => Some(enable_and_select(mode, target_layout)),
+        None => Some(SwitchOutcome::NoInputMode(mode_id.to_string())),
+    }
+}
+
+/// Selects the best enabled input source for `target_layout`, trying the candidates
+/// in order until one is accepted. When only disabled sources match, the best one is
+/// enabled first if the config allows it.
+unsafe fn select_layout_source(target_layout: &str) -> SwitchOutcome {
+    let catalogue = sources::catalogue();
+    let candidates = candidate_sources(&catalogue, target_layout, true);
+    let mut enabled = candidates
+        .iter()
+        .filter(|candidate| candidate.source.enabled)
+        .peekable();
+
+    if enabled.peek().is_none() {
+        return match candidates.first() {
+            Some(candidate) => enable_and_select(candidate.source, target_layout),
+            None => SwitchOutcome::NotInstalled,
+        };
+    }
+
+    let mut outcome = SwitchOutcome::NotInstalled;
+    for candidate in enabled {
+        outcome = select_source(candidate.source.as_raw());
+        if !outcome.is_failure() {
+            return outcome;
+        }
+    }
+    outcome
+}
+
+/// Selects the installed input source whose id is exactly `source_id`.
+unsafe fn select_input_source(source_id: &str) -> SwitchOutcome {
+    let catalogue = sources::catalogue();
+    match catalogue.get(source_id) {
+        Some(source) if source.enabled => select_source(source.as_raw()),
+        Some(source) => enable_and_select(source, source_id),
+        None => SwitchOutcome::NotInstalled,
+    }
+}
+
+/// Enables the disabled input source `source`, which stands for `target`, and selects
+/// it, provided `enable_missing_layouts` is set in the config.
+///
+/// The catalogue is listed again afterwards; `source` stays valid as long as the
+/// caller holds the catalogue it came from.
+unsafe fn enable_and_select(source: &InputSource, target: &str) -> SwitchOutcome {
+    let source_id = source.id.clone();
+    if !enable_missing_layouts() || !source.enable_capable {
+        return SwitchOutcome::NotEnabled(source_id);
+    }
+
+    let status = TISEnableInputSource(source.as_raw());
+    if status != 0 {
+        println!(
+            "Could not enable input source {} for '{}' (status {})",
+            source_id, target, status
+        );
+        return SwitchOutcome::Rejected(status);
+    }
+    println!(
+        "Enabled input source {} ({}) for '{}'; it is now listed in System Settings",
+        source_id, source.name, target
+    );
+
+    let outcome = select_source(source.as_raw());
+    sources::invalidate();
+    outcome
+}
+
+unsafe fn input_source_id(source: id) -> Option<String> {
+    let id_key = NSString::alloc(nil).init_str(K_TIS_PROPERTY_INPUT_SOURCE_ID);
+    let source_id = TISGetInputSourceProperty(source, id_key);
+    if source_id == nil {
+        return None;
+    }
+
+    let c_string: *const c_char = msg_send![source_id, UTF8String];
+    if c_string.is_null() {
+        return None;
+    }
+    Some(CStr::from_ptr(c_string).to_string_lossy().to_string())
+}
+
+unsafe fn select_source(source: id) -> SwitchOutcome {
+    let source_id = match input_source_id(source) {
+        Some(source_id) => source_id,
+        None => return SwitchOutcome::NotInstalled,
+    };
+
+    if (*std::ptr::addr_of!(state::CURRENT_INPUT_SOURCE_ID)).as_deref() == Some(&source_id)
+        && (*std::ptr::addr_of!(state::PENDING_SWITCH)).is_none()
+    {
+        return SwitchOutcome::AlreadyActive(source_id);
+    }
+
+    match TISSelectInputSource(source) {
+        0 => SwitchOutcome::Switched(source_id),
+        status => SwitchOutcome::Rejected(status),
     }
 }
 
 /// Attempts to switch the system keyboard layout to the specified target layout string.
+/// The switch is verified asynchronously and retried a few times if it fails.
 ///
 /// # Safety
 ///
 /// This function is unsafe because:
 /// 1. It calls numerous FFI functions (TIS... and CF...) to interact with macOS APIs.
 /// 2. It directly manipulates system-wide state (the active keyboard layout).
-/// 3. It calls `monitor::update_keyboard_layout()`, which is also unsafe.
 ///     The caller is responsible for ensuring that `target_layout` represents a valid
 ///     intended layout name or identifier.
-pub unsafe fn switch_to_layout(target_layout: &str) {
-    let target_upper = target_layout.to_uppercase();
-
-    let search_patterns = match target_upper.as_str() {
-        "US" | "EN" | "ENGLISH" => vec!["com.apple.keylayout.US", "com.apple.keylayout.ABC", "US"],
-        "RU" | "RUSSIAN" => vec!["com.apple.keylayout.Russian", "Russian"],
-        "CN" | "CHINESE" | "PINYIN" | "ZH" => vec![
-            "com.apple.keylayout.PinyinSimplified",
-            "com.apple.inputmethod.SCIM.Pinyin",
-            "Pinyin",
-            "简体",
-        ],
-        "HI" | "HINDI" | "DEVANAGARI" => vec![
-            "com.apple.keylayout.Devanagari-QWERTY",
-            "com.apple.keylayout.Hindi-QWERTY",
-            "Hindi",
-            "Devanagari",
-            "देवनागरी",
-        ],
-        _ => vec![target_layout],
+pub unsafe fn switch_to_layout(target_layout: &str) -> SwitchOutcome {
+    request_switch(SwitchTarget::Layout(target_layout.to_string()), 1)
+}
+
+/// Selects the input source whose id is exactly `source_id`, like `switch_to_layout`.
+///
+/// # Safety
+///
+/// This function is unsafe because it calls TIS and CF FFI functions to look up and
+/// select the input source.
+pub unsafe fn switch_to_input_source_id(source_id: &str) -> SwitchOutcome {
+    request_switch(SwitchTarget::InputSource(source_id.to_string()), 1)
+}
+
+/// Switches to `target_layout` once and waits until the layout has changed, for
+/// callers without a run loop to verify the switch later, such as the command line.
+/// There are no retries and no failure hook; the outcome is the final one.
+///
+/// # Safety
+///
+/// This function is unsafe because it calls TIS and CF FFI functions and writes the
+/// current layout to `state`.
+pub unsafe fn switch_to_layout_now(target_layout: &str) -> SwitchOutcome {
+    let outcome = select_layout(target_layout);
+    let source_id = match outcome {
+        SwitchOutcome::Switched(ref source_id) => source_id.clone(),
+        _ => return outcome,
     };
 
-    if target_upper == "US" || target_upper == "EN" || target_upper == "ENGLISH" {
-        let us_lang =
-            CFStringCreateWithCString(nil, c"en".as_ptr() as *const c_char, K_UTF8_ENCODING);
-        if us_lang != nil {
-            let us_source = TISCopyInputSourceForLanguage(us_lang);
-            CFRelease(us_lang);
-
-            if us_source != nil {
-                let result = TISSelectInputSource(us_source);
-                CFRelease(us_source);
-
-                if result == 0 {
-                    println!("Successfully switched to layout: {}", target_layout);
-                    thread::sleep(Duration::from_millis(100));
-                    monitor::update_keyboard_layout();
-                    return;
-                }
-            }
+    let started = Instant::now();
+    loop {
+        monitor::update_keyboard_layout();
+        if (*std::ptr::addr_of!(state::CURRENT_INPUT_SOURCE_ID)).as_deref() == Some(&source_id) {
+            return outcome;
+        }
+        if started.elapsed() >= SWITCH_VERIFY_TIMEOUT {
+            return SwitchOutcome::VerifyTimeout;
         }
+        thread::sleep(SWITCH_POLL_INTERVAL);
     }
+}
 
-    let input_sources = TISCreateInputSourceList(nil, true);
-    if input_sources != nil {
-        let count = CFArrayGetCount(input_sources);
-
-        for i in 0..count {
-            let source = CFArrayGetValueAtIndex(input_sources, i);
-            if source != nil {
-                let id_key = NSString::alloc(nil).init_str(K_TIS_PROPERTY_INPUT_SOURCE_ID);
-                let name_key = NSString::alloc(nil).init_str(K_TIS_PROPERTY_LOCALIZED_NAME);
-
-                let source_id = TISGetInputSourceProperty(source, id_key);
-                let source_name = TISGetInputSourceProperty(source, name_key);
-
-                let mut found = false;
-
-                if source_id != nil && CFGetTypeID(source_id) == CFStringGetTypeID() {
-                    let length = CFStringGetLength(source_id);
-                    if length > 0 {
-                        let buffer_size = (length + 1) * 4;
-                        let mut buffer = vec![0u8; buffer_size as usize];
-
-                        if CFStringGetCString(
-                            source_id,
-                            buffer.as_mut_ptr() as *mut c_char,
-                            buffer_size,
-                            K_UTF8_ENCODING,
-                        ) {
-                            if let Ok(id_str) =
-                                CStr::from_ptr(buffer.as_ptr() as *const c_char).to_str()
-                            {
-                                for pattern in &search_patterns {
-                                    if id_str.contains(pattern) {
-                                        found = true;
-                                        break;
-                                    }
-                                }
-                            }
-                        }
-                    }
-                }
+unsafe fn request_switch(target: SwitchTarget, attempt: u32) -> SwitchOutcome {
+    state::SWITCH_RETRY = None;
 
-                if !found && source_name != nil && CFGetTypeID(source_name) == CFStringGetTypeID() {
-                    let length = CFStringGetLength(source_name);
-                    if length > 0 {
-                        let buffer_size = (length + 1) * 4;
-                        let mut buffer = vec![0u8; buffer_size as usize];
-
-                        if CFStringGetCString(
-                            source_name,
-                            buffer.as_mut_ptr() as *mut c_char,
-                            buffer_size,
-                            K_UTF8_ENCODING,
-                        ) {
-                            if let Ok(name_str) =
-                                CStr::from_ptr(buffer.as_ptr() as *const c_char).to_str()
-                            {
-                                for pattern in &search_patterns {
-                                    if name_str.to_uppercase().contains(&pattern.to_uppercase()) {
-                                        found = true;
-                                        break;
-                                    }
-                                }
-                            }
-                        }
-                    }
-                }
+    let outcome = match target {
+        SwitchTarget::Layout(ref layout) => select_layout(layout),
+        SwitchTarget::InputSource(ref source_id) => select_input_source(source_id),
+    };
 
-                if found {
-                    let result = TISSelectInputSource(source);
-                    if result == 0 {
-                        println!("Successfully switched to layout: {}", target_layout);
-                        CFRelease(input_sources);
-                        thread::sleep(Duration::from_millis(100));
-                        monitor::update_keyboard_layout();
-                        return;
-                    }
-                }
+    let app = (*std::ptr::addr_of!(state::CURRENT_APP)).clone();
+    match outcome {
+        SwitchOutcome::Switched(ref source_id) => {
+            println!("Successfully switched to {}: {}", target, source_id);
+            control::emit(
+                "switched",
+                json!({ "target": target.to_string(), "input_source": source_id }),
+            );
+            begin_verification(target, source_id.clone(), attempt, app);
+        }
+        SwitchOutcome::AlreadyActive(_) => {}
+        _ => retry_or_report(target, outcome.clone(), attempt, app),
+    }
+    outcome
+}
+
+/// Records a selected input source as our own switch and waits for the system to
+/// report it, instead of blocking until the change is visible.
+unsafe fn begin_verification(
+    target: SwitchTarget,
+    source_id: String,
+    attempt: u32,
+    app: Option<String>,
+) {
+    let now = Instant::now();
+    state::LAST_OWN_SWITCH = Some((source_id.clone(), now));
+    state::PENDING_SWITCH = Some(PendingSwitch {
+        target,
+        source_id,
+        attempt,
+        app,
+        since: now,
+    });
+    observer::schedule_switch_verification(SWITCH_VERIFY_TIMEOUT);
+}
+
+/// Schedules another attempt after a failed one, or reports the failure once the
+/// attempts are used up or retrying cannot help.
+unsafe fn retry_or_report(
+    target: SwitchTarget,
+    outcome: SwitchOutcome,
+    attempt: u32,
+    app: Option<String>,
+) {
+    let delay = match retry_delay(attempt) {
+        Some(delay) if outcome.is_retryable() => delay,
+        _ => return report_failure(target, outcome, attempt, app),
+    };
+
+    println!(
+        "Failed to switch to {} ({}), retrying in {} ms...",
+        target,
+        outcome,
+        delay.as_millis()
+    );
+    state::SWITCH_RETRY = Some(PendingRetry {
+        target,
+        attempt: attempt + 1,
+        app,
+    });
+    observer::schedule_switch_retry(delay);
+}
+
+unsafe fn report_failure(
+    target: SwitchTarget,
+    outcome: SwitchOutcome,
+    attempts: u32,
+    app: Option<String>,
+) {
+    println!(
+        "Failed to switch to {}: {} (after {} attempt{})",
+        target,
+        outcome,
+        attempts,
+        if attempts == 1 { "" } else { "s" }
+    );
+
+    if let Some(command) = (*std::ptr::addr_of!(state::CONFIG))
+        .as_ref()
+        .and_then(|config| config.on_switch_failure.as_deref())
+    {
+        let target_value = match target {
+            SwitchTarget::Layout(ref layout) => layout.clone(),
+            SwitchTarget::InputSource(ref source_id) => source_id.clone(),
+        };
+        hooks::run_hook(
+            "on_switch_failure",
+            command,
+            &[
+                ("LANGUAGE_HANDLER_TARGET", target_value),
+                ("LANGUAGE_HANDLER_OUTCOME", outcome.as_str().to_string()),
+                ("LANGUAGE_HANDLER_MESSAGE", outcome.to_string()),
+                ("LANGUAGE_HANDLER_APP", app.clone().unwrap_or_default()),
+            ],
+        );
+    }
+
+    control::emit(
+        "switch_failed",
+        json!({
+            "target": target.to_string(),
+            "outcome": outcome.as_str(),
+            "message": outcome.to_string(),
+            "app": app,
+        }),
+    );
+    state::LAST_SWITCH_FAILURE = Some(SwitchFailure {
+        target,
+        outcome,
+        app,
+        attempts,
+        at: SystemTime::now(),
+    });
+}
+
+/// Makes the attempt scheduled by a failed switch, unless focus has moved to another
+/// application in the meantime.
+///
+/// # Safety
+///
+/// This function is unsafe because it reads and writes `static mut` variables in `state`
+/// and calls TIS FFI functions.
+pub unsafe fn retry_switch() {
+    let retry = match (*std::ptr::addr_of_mut!(state::SWITCH_RETRY)).take() {
+        Some(retry) => retry,
+        None => return,
+    };
+
+    if retry.app != *std::ptr::addr_of!(state::CURRENT_APP) {
+        println!(
+            "Focus moved, dropping retry of the switch to {}",
+            retry.target
+        );
+        return;
+    }
+
+    request_switch(retry.target, retry.attempt);
+}
+
+/// Stops waiting for and retrying our own switch, e.g. because the user picked a
+/// layout by hand in the meantime.
+///
+/// # Safety
+///
+/// This function is unsafe because it writes to `static mut` variables in `state`.
+pub unsafe fn cancel_pending_switch() {
+    state::PENDING_SWITCH = None;
+    state::SWITCH_RETRY = None;
+}
+
+/// Checks whether the switch in flight has taken effect, once the current layout has
+/// been read again. Until `timed_out`, an unconfirmed switch stays pending.
+///
+/// # Safety
+///
+/// This function is unsafe because it reads and writes `static mut` variables in `state`.
+pub unsafe fn verify_pending_switch(timed_out: bool) {
+    let pending = match &*std::ptr::addr_of!(state::PENDING_SWITCH) {
+        Some(pending) => pending,
+        None => return,
+    };
+
+    let current = (*std::ptr::addr_of!(state::CURRENT_INPUT_SOURCE_ID)).as_deref();
+    if current == Some(pending.source_id.as_str()) {
+        state::PENDING_SWITCH = None;
+    } else if timed_out && pending.since.elapsed() >= SWITCH_VERIFY_TIMEOUT {
+        println!(
+            "Switch to input source '{}' was not confirmed, current one is '{}'",
+            pending.source_id,
+            current.unwrap_or("unknown")
+        );
+        if let Some(pending) = (*std::ptr::addr_of_mut!(state::PENDING_SWITCH)).take() {
+            retry_or_report(
+                pending.target,
+                SwitchOutcome::VerifyTimeout,
+                pending.attempt,
+                pending.app,
+            );
+        }
+    }
+}
+
+/// Classifies a layout change notification: returns `true` when the layout changed
+/// and the change was not caused by our own switch.
+///
+/// # Safety
+///
+/// This function is unsafe because it reads and writes `static mut` variables in `state`.
+///     The caller must ensure that access to `state` variables is synchronized if
+///     the application is or becomes multi-threaded.
+pub unsafe fn is_manual_switch(previous_source_id: Option<&str>) -> bool {
+    let current = &*std::ptr::addr_of!(state::CURRENT_INPUT_SOURCE_ID);
+    if current.as_deref() == previous_source_id {
+        return false;
+    }
+
+    if let Some((source_id, at)) = (*std::ptr::addr_of_mut!(state::LAST_OWN_SWITCH)).take() {
+        if current.as_deref() == Some(source_id.as_str()) && at.elapsed() < OWN_SWITCH_WINDOW {
+            return false;
+        }
+    }
+
+    true
+}
+
+/// Treats the current layout as the user's choice and pauses automatic switching for
+/// the current application and window until the grace period ends or focus moves.
+///
+/// # Safety
+///
+/// This function is unsafe because it reads and writes `static mut` variables in `state`.
+pub unsafe fn start_manual_override() {
+    if let Some(app_name) = &*std::ptr::addr_of!(state::CURRENT_APP) {
+        println!(
+            "Manual layout change in '{}', pausing automatic switching",
+            app_name
+        );
+        state::MANUAL_OVERRIDE = Some(ManualOverride {
+            app: app_name.clone(),
+            window: state::CURRENT_WINDOW,
+            since: Instant::now(),
+        });
+    }
+}
+
+/// Ends the pause started by a manual layout change and resets the enforcement loop
+/// guard; called whenever focus moves.
+///
+/// # Safety
+///
+/// This function is unsafe because it writes to `static mut` variables in `state`.
+pub unsafe fn reset_focus_state() {
+    state::MANUAL_OVERRIDE = None;
+    state::ENFORCEMENT_GUARD = Some(EnforcementGuard::new());
+}
+
+pub fn is_overlay(config: &Config, app_name: &str) -> bool {
+    config
+        .overlays
+        .iter()
+        .any(|overlay| app_matches(app_name, overlay))
+}
+
+/// Tracks focus moving into and out of overlay applications. When an overlay gains
+/// focus, the layout of the application it covers is saved; when focus comes back to
+/// that application, the saved layout is restored as it was, without consulting rules
+/// or memory. Returns `true` in the latter case.
+///
+/// # Safety
+///
+/// This function is unsafe because it reads and writes `static mut` variables in `state`
+/// and may call `switch_to_input_source_id`.
+pub unsafe fn track_overlay(previous_app: Option<&str>) -> bool {
+    let (app_name, config) = match (
+        &*std::ptr::addr_of!(state::CURRENT_APP),
+        &*std::ptr::addr_of!(state::CONFIG),
+    ) {
+        (Some(app_name), Some(config)) if !config.overlays.is_empty() => (app_name, config),
+        _ => return false,
+    };
+
+    if is_overlay(config, app_name) {
+        let covered = previous_app.filter(|previous| !is_overlay(config, previous));
+        if let (Some(covered), Some(source_id), None) = (
+            covered,
+            &*std::ptr::addr_of!(state::CURRENT_INPUT_SOURCE_ID),
+            &*std::ptr::addr_of!(state::OVERLAY_RETURN),
+        ) {
+            state::OVERLAY_RETURN = Some(OverlayReturn {
+                app: covered.to_string(),
+                source_id: source_id.clone(),
+                manual_override: (*std::ptr::addr_of_mut!(state::MANUAL_OVERRIDE)).take(),
+            });
+        }
+        return false;
+    }
+
+    let overlay_return = match (*std::ptr::addr_of_mut!(state::OVERLAY_RETURN)).take() {
+        Some(overlay_return) if overlay_return.app == *app_name => overlay_return,
+        _ => return false,
+    };
+
+    if !state::PAUSED
+        && (*std::ptr::addr_of!(state::CURRENT_INPUT_SOURCE_ID)).as_deref()
+            != Some(&overlay_return.source_id)
+    {
+        println!(
+            "Overlay closed, restoring layout '{}' in '{}'...",
+            overlay_return.source_id, app_name
+        );
+        switch_to_input_source_id(&overlay_return.source_id);
+    }
+    state::MANUAL_OVERRIDE = overlay_return.manual_override;
+    state::ENFORCEMENT_GUARD = Some(EnforcementGuard::new());
+    true
+}
+
+unsafe fn manual_override_active(app_name: &str, grace_period: Option<Duration>) -> bool {
+    match &*std::ptr::addr_of!(state::MANUAL_OVERRIDE) {
+        Some(manual) => manual.is_active(app_name, state::CURRENT_WINDOW, grace_period),
+        None => false,
+    }
+}
+
+fn memory_mode() -> MemoryMode {
+    unsafe {
+        (*std::ptr::addr_of!(state::CONFIG))
+            .as_ref()
+            .map_or(MemoryMode::Off, |config| config.memory)
+    }
+}
+
+/// Records the active input source as the last one used in the current application and window.
+/// Nothing is recorded while a switch is in flight, since the active source is about to change.
+///
+/// # Safety
+///
+/// This function is unsafe because it reads and writes `static mut` variables in `state`.
+///     The caller must ensure that access to `state` variables is synchronized if
+///     the application is or becomes multi-threaded.
+pub unsafe fn remember_current_layout() {
+    if memory_mode() == MemoryMode::Off || (*std::ptr::addr_of!(state::PENDING_SWITCH)).is_some() {
+        return;
+    }
+
+    if let (Some(app_name), Some(source_id), Some(memory)) = (
+        &*std::ptr::addr_of!(state::CURRENT_APP),
+        &*std::ptr::addr_of!(state::CURRENT_INPUT_SOURCE_ID),
+        &mut *std::ptr::addr_of_mut!(state::LAYOUT_MEMORY),
+    ) {
+        memory.remember(app_name, state::CURRENT_WINDOW, source_id);
+    }
+}
+
+/// Drops remembered layouts of windows that have been closed.
+///
+/// # Safety
+///
+/// This function is unsafe because it calls CoreGraphics FFI functions and writes to
+/// `state::LAYOUT_MEMORY`.
+pub unsafe fn forget_closed_windows() {
+    if memory_mode() != MemoryMode::Window {
+        return;
+    }
+
+    if let Some(memory) = &mut *std::ptr::addr_of_mut!(state::LAYOUT_MEMORY) {
+        memory.retain_windows(&monitor::live_window_ids());
+    }
+}
+
+/// Restores the layout remembered for the current window or application.
+/// Returns `false` when nothing is remembered and the rules should decide.
+unsafe fn restore_remembered_layout(app_name: &str, mode: MemoryMode) -> bool {
+    let remembered = match &*std::ptr::addr_of!(state::LAYOUT_MEMORY) {
+        Some(memory) => memory.recall(mode, app_name, state::CURRENT_WINDOW),
+        None => None,
+    };
+
+    let source_id = match remembered {
+        Some(source_id) => source_id.to_string(),
+        None => return false,
+    };
+
+    if (*std::ptr::addr_of!(state::CURRENT_INPUT_SOURCE_ID)).as_deref() != Some(&source_id) {
+        println!(
+            "Application '{}' is active, restoring remembered layout '{}'...",
+            app_name, source_id
+        );
+        switch_to_input_source_id(&source_id);
+    }
+    true
+}
+
+unsafe fn rule_context(app_name: &str) -> RuleContext<'_> {
+    RuleContext {
+        app: app_name,
+        process: (*std::ptr::addr_of!(state::CURRENT_PROCESS)).as_ref(),
+        now: LocalTime::now(),
+        workspace: (*std::ptr::addr_of!(state::CURRENT_WORKSPACE)).as_deref(),
+        output: (*std::ptr::addr_of!(state::CURRENT_OUTPUT)).as_deref(),
+        window: (*std::ptr::addr_of!(state::CURRENT_WINDOW_ROLE)).as_ref(),
+    }
+}
+
+fn is_excluded(config: &Config, app_name: &str) -> bool {
+    config
+        .exclude
+        .iter()
+        .any(|excluded| app_matches(app_name, excluded))
+}
+
+unsafe fn enforcement_suspended() -> bool {
+    (*std::ptr::addr_of!(state::ENFORCEMENT_GUARD))
+        .as_ref()
+        .is_some_and(|guard| guard.is_suspended())
+}
+
+/// Whether the current layout is one of the layouts the rule allows.
+unsafe fn rule_satisfied(current_layout: &str, current_id: Option<&str>, rule: &Rule) -> bool {
+    rule.layouts
+        .iter()
+        .any(|layout| is_target_layout(current_layout, current_id, layout))
+}
+
+unsafe fn apply_rule(app_name: &str, rule: &Rule) {
+    let current_id = (*std::ptr::addr_of!(state::CURRENT_INPUT_SOURCE_ID)).as_deref();
+    if let Some(ref current_layout) = *std::ptr::addr_of!(state::CURRENT_KEYBOARD_LAYOUT) {
+        if !rule_satisfied(current_layout, current_id, rule) {
+            if rule.app.as_deref() == Some(app_name) && !rule.has_process_condition() {
+                println!(
+                    "Application '{}' is active, switching to layout '{}'...",
+                    app_name,
+                    rule.primary_layout()
+                );
+            } else {
+                println!(
+                    "Application '{}' (rule: '{}') is active, switching to layout '{}'...",
+                    app_name,
+                    rule,
+                    rule.primary_layout()
+                );
             }
+            switch_to_layout(rule.primary_layout());
+        }
+    }
+}
+
+unsafe fn current_project_layouts() -> Option<ProjectLayouts> {
+    (*std::ptr::addr_of!(state::CURRENT_DIRECTORY))
+        .as_deref()
+        .and_then(project::find_project_layouts)
+}
+
+unsafe fn apply_project_layouts(app_name: &str, project: &ProjectLayouts) {
+    let rule = Rule::with_layouts(None, project.layouts.clone());
+    let current_id = (*std::ptr::addr_of!(state::CURRENT_INPUT_SOURCE_ID)).as_deref();
+    if let Some(ref current_layout) = *std::ptr::addr_of!(state::CURRENT_KEYBOARD_LAYOUT) {
+        if !rule_satisfied(current_layout, current_id, &rule) {
+            println!(
+                "Application '{}' is in a project ('{}'), switching to layout '{}'...",
+                app_name,
+                project.path.display(),
+                rule.primary_layout()
+            );
+            switch_to_layout(rule.primary_layout());
+        }
+    }
+}
+
+/// Switches straight back when the focused application has an enforced rule and the
+/// layout was changed away from it. Gives up until focus moves if the layout keeps
+/// being changed, so two tools cannot fight forever.
+/// Returns `false` when no enforced rule applies to the focused application, or while
+/// paused.
+///
+/// # Safety
+///
+/// This function is unsafe because:
+/// 1. It reads and writes `static mut` variables in `state` via raw pointers.
+/// 2. It calls `switch_to_layout`, which is part of an unsafe API.
+pub unsafe fn enforce_rule_layout() -> bool {
+    if state::PAUSED {
+        return false;
+    }
+
+    let (app_name, config) = match (
+        &*std::ptr::addr_of!(state::CURRENT_APP),
+        &*std::ptr::addr_of!(state::CONFIG),
+    ) {
+        (Some(app_name), Some(config)) => (app_name, config),
+        _ => return false,
+    };
+
+    if is_excluded(config, app_name) {
+        return false;
+    }
+
+    let rule = match find_rule(&config.rules, &rule_context(app_name)) {
+        Some(rule) if rule.enforce => rule,
+        _ => return false,
+    };
+
+    let current_id = (*std::ptr::addr_of!(state::CURRENT_INPUT_SOURCE_ID)).as_deref();
+    if let Some(ref current_layout) = *std::ptr::addr_of!(state::CURRENT_KEYBOARD_LAYOUT) {
+        if rule_satisfied(current_layout, current_id, rule) {
+            return true;
         }
+    }
 
-        CFRelease(input_sources);
+    let guard = (*std::ptr::addr_of_mut!(state::ENFORCEMENT_GUARD))
+        .get_or_insert_with(EnforcementGuard::new);
+    if guard.is_suspended() {
+        return false;
+    }
+    if !guard.allow_revert() {
+        println!(
+            "Layout in '{}' keeps changing, not enforcing '{}' until focus moves",
+            app_name, rule
+        );
+        return false;
     }
 
-    println!("Failed to switch to layout: {}", target_layout);
+    println!(
+        "Application '{}' enforces layout '{}', switching back...",
+        app_name,
+        rule.primary_layout()
+    );
+    switch_to_layout(rule.primary_layout());
+    true
 }
 
-/// Checks configured rules and, if necessary, initiates a keyboard layout switch.
+/// Restores a remembered layout or checks configured rules and, if necessary,
+/// initiates a keyboard layout switch. Applications without a rule get the layout of
+/// the nearest `.language-handler` project file, then the default layout, if one is
+/// configured; excluded applications and a layout the user has
+/// just picked by hand are never touched. Enforced rules take precedence over both
+/// memory and manual changes, rules for a window type (a dialog, a sheet) over memory.
+/// Nothing is switched while paused.
 ///
 /// # Safety
 ///
 /// This function is unsafe because:
-/// 1. It reads from `static mut` variables (`state::CURRENT_APP`,
-///    `state::APP_LAYOUT_RULES`, `state::CURRENT_KEYBOARD_LAYOUT`) via raw pointers.
+/// 1. It reads from `static mut` variables (`state::CURRENT_APP`, `state::CONFIG`,
+///    `state::LAYOUT_MEMORY`, `state::CURRENT_KEYBOARD_LAYOUT`) via raw pointers.
 /// 2. It calls `is_target_layout` and `switch_to_layout`, which are part of an unsafe API.
 ///
 ///     The caller must ensure that access to `state` variables is synchronized if
 ///     the application is or becomes multi-threaded.
 pub unsafe fn check_and_switch_layout_by_rules() {
-    if let (Some(ref app_name), Some(ref rules)) = (
+    if state::PAUSED {
+        return;
+    }
+
+    if let (Some(ref app_name), Some(ref config)) = (
         &*std::ptr::addr_of!(state::CURRENT_APP),
-        &*std::ptr::addr_of!(state::APP_LAYOUT_RULES),
+        &*std::ptr::addr_of!(state::CONFIG),
     ) {
-        if let Some(target_layout) = rules.get(app_name) {
+        if let Some(excluded) = config
+            .exclude
+            .iter()
+            .find(|excluded| app_matches(app_name, excluded))
+        {
+            println!(
+                "Application '{}' (excluded: '{}') is active, leaving layout unchanged",
+                app_name, excluded
+            );
+            return;
+        }
+
+        let rule = find_rule(&config.rules, &rule_context(app_name));
+
+        if let Some(rule) = rule {
+            if rule.enforce && !enforcement_suspended() {
+                apply_rule(app_name, rule);
+                return;
+            }
+        }
+
+        if manual_override_active(app_name, config.grace_period) {
+            println!(
+                "Application '{}' has a manually selected layout, leaving it unchanged",
+                app_name
+            );
+            return;
+        }
+
+        // Memory would otherwise keep a schedule from ever taking effect in an
+        // application that has been used before.
+        if let Some(rule) =
+            rule.filter(|rule| rule.has_window_condition() || rule.has_schedule_condition())
+        {
+            apply_rule(app_name, rule);
+            return;
+        }
+
+        if restore_remembered_layout(app_name, config.memory) {
+            return;
+        }
+
+        if let Some(rule) = rule {
+            apply_rule(app_name, rule);
+            return;
+        }
+
+        if let Some(project) = current_project_layouts() {
+            apply_project_layouts(app_name, &project);
+            return;
+        }
+
+        if let Some(ref default_layout) = config.default_layout {
+            let current_id = (*std::ptr::addr_of!(state::CURRENT_INPUT_SOURCE_ID)).as_deref();
             if let Some(ref current_layout) = *std::ptr::addr_of!(state::CURRENT_KEYBOARD_LAYOUT) {
-                if !is_target_layout(current_layout, target_layout) {
+                if !is_target_layout(current_layout, current_id, default_layout) {
                     println!(
-                        "Application '{}' is active, switching to layout '{}'...",
-                        app_name, target_layout
+                        "Application '{}' has no rule, switching to default layout '{}'...",
+                        app_name, default_layout
                     );
-                    switch_to_layout(target_layout);
+                    switch_to_layout(default_layout);
                 }
             }
-            return;
         }
+    }
+}
 
-        for (rule_app, target_layout) in rules {
-            if app_name.contains(rule_app) || rule_app.contains(app_name) {
-                if let Some(ref current_layout) =
-                    *std::ptr::addr_of!(state::CURRENT_KEYBOARD_LAYOUT)
-                {
-                    if !is_target_layout(current_layout, target_layout) {
-                        println!(
-                            "Application '{}' (rule: '{}') is active, switching to layout '{}'...",
-                            app_name, rule_app, target_layout
-                        );
-                        switch_to_layout(target_layout);
-                    }
-                }
-                return;
-            }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::config;
+
+    /// The only test that uses the global state.
+    #[test]
+    fn overlay_return_restores_the_covered_application() {
+        unsafe {
+            state::CONFIG =
+                Some(config::parse_config(r#"{ "rules": {}, "overlays": ["Alfred"] }"#).unwrap());
+            state::CURRENT_INPUT_SOURCE_ID = Some("com.apple.keylayout.Russian".to_string());
+
+            state::CURRENT_APP = Some("Alfred".to_string());
+            assert!(!track_overlay(Some("Safari")));
+            let saved = (*std::ptr::addr_of!(state::OVERLAY_RETURN))
+                .clone()
+                .unwrap();
+            assert_eq!(saved.app, "Safari");
+            assert_eq!(saved.source_id, "com.apple.keylayout.Russian");
+
+            // Moving on to another application than the covered one drops the return.
+            state::CURRENT_APP = Some("Mail".to_string());
+            assert!(!track_overlay(Some("Alfred")));
+            assert!((*std::ptr::addr_of!(state::OVERLAY_RETURN)).is_none());
+
+            state::CURRENT_APP = Some("Alfred".to_string());
+            assert!(!track_overlay(Some("Safari")));
+            state::CURRENT_APP = Some("Safari".to_string());
+            assert!(track_overlay(Some("Alfred")));
+            assert!((*std::ptr::addr_of!(state::OVERLAY_RETURN)).is_none());
+
+            state::CONFIG = None;
+            state::CURRENT_APP = None;
+            state::CURRENT_INPUT_SOURCE_ID = None;
         }
     }
 }
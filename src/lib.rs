@@ -5,28 +5,68 @@ use cocoa::foundation::NSAutoreleasePool;
 pub mod core;
 
 pub(crate) mod state {
-    use std::collections::HashMap;
+    use crate::core::accessibility::WindowRole;
+    use crate::core::config::Config;
+    use crate::core::enforce::EnforcementGuard;
+    use crate::core::memory::{LayoutMemory, ManualOverride, OverlayReturn};
+    use crate::core::observer::PendingFocus;
+    use crate::core::outcome::SwitchFailure;
+    use crate::core::process::ProcessInfo;
+    use crate::core::sources::SourceCatalogue;
+    use crate::core::switcher::{PendingRetry, PendingSwitch};
+    use cocoa::base::id;
+    use std::path::PathBuf;
+    use std::rc::Rc;
+    use std::time::Instant;
 
     pub(crate) static mut CURRENT_APP: Option<String> = None;
+    pub(crate) static mut CURRENT_PID: Option<i32> = None;
+    pub(crate) static mut CURRENT_WINDOW: Option<u32> = None;
+    pub(crate) static mut CURRENT_WINDOW_ROLE: Option<WindowRole> = None;
+    pub(crate) static mut CURRENT_WORKSPACE: Option<String> = None;
+    pub(crate) static mut CURRENT_OUTPUT: Option<String> = None;
+    pub(crate) static mut CURRENT_PROCESS: Option<ProcessInfo> = None;
+    pub(crate) static mut CURRENT_DIRECTORY: Option<PathBuf> = None;
     pub(crate) static mut CURRENT_KEYBOARD_LAYOUT: Option<String> = None;
-    pub(crate) static mut APP_LAYOUT_RULES: Option<HashMap<String, String>> = None;
+    pub(crate) static mut CURRENT_INPUT_SOURCE_ID: Option<String> = None;
+    pub(crate) static mut SOURCE_CATALOGUE: Option<Rc<SourceCatalogue>> = None;
+    pub(crate) static mut CONFIG: Option<Config> = None;
+    pub(crate) static mut LAYOUT_MEMORY: Option<LayoutMemory> = None;
+    pub(crate) static mut MANUAL_OVERRIDE: Option<ManualOverride> = None;
+    pub(crate) static mut OVERLAY_RETURN: Option<OverlayReturn> = None;
+    pub(crate) static mut PENDING_FOCUS: Option<PendingFocus> = None;
+    pub(crate) static mut PENDING_SWITCH: Option<PendingSwitch> = None;
+    pub(crate) static mut SWITCH_RETRY: Option<PendingRetry> = None;
+    pub(crate) static mut LAST_SWITCH_FAILURE: Option<SwitchFailure> = None;
+    pub(crate) static mut OBSERVER: Option<id> = None;
+    pub(crate) static mut FOCUS_CHECK_TIMER: Option<id> = None;
+    pub(crate) static mut SCHEDULE_TIMER: Option<id> = None;
+    pub(crate) static mut WATCHING_SPACES: bool = false;
+    pub(crate) static mut LAST_OWN_SWITCH: Option<(String, Instant)> = None;
+    pub(crate) static mut ENFORCEMENT_GUARD: Option<EnforcementGuard> = None;
+    pub(crate) static mut PAUSED: bool = false;
 }
 
 pub fn run() {
     let config = core::config::load_or_create_config();
 
     unsafe {
-        state::APP_LAYOUT_RULES = Some(config);
+        state::CONFIG = Some(config);
+        state::LAYOUT_MEMORY = Some(core::memory::LayoutMemory::new());
+        state::ENFORCEMENT_GUARD = Some(core::enforce::EnforcementGuard::new());
 
         let _pool = NSAutoreleasePool::new(nil);
 
         let app = NSApp();
         app.setActivationPolicy_(NSApplicationActivationPolicyProhibited);
 
+        core::switcher::check_aliases();
         core::observer::setup_observers();
+        core::control::start();
 
         core::monitor::update_active_window();
         core::monitor::update_keyboard_layout();
+        core::switcher::remember_current_layout();
 
         if let (Some(app), Some(layout)) = (
             &*std::ptr::addr_of!(state::CURRENT_APP),
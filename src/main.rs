@@ -1,5 +1,13 @@
-use language_handler::run;
+use language_handler::core::cli;
 
 fn main() {
-    run();
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let code = match cli::parse_args(&args) {
+        Ok(command) => cli::execute(command),
+        Err(e) => {
+            eprintln!("Error: {}\n\n{}", e, cli::USAGE);
+            2
+        }
+    };
+    std::process::exit(code);
 }